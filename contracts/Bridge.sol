@@ -18,6 +18,17 @@ contract FrostyBridge {
         uint256 jobId
     );
 
+    /// @notice Emitted instead of `FunctionInvoked` when the caller requested a callback.
+    /// @param callback Contract implementing `IFrostyCallback` that receives the result.
+    event FunctionInvokedWithCallback(
+        address indexed caller,
+        bytes32 indexed functionId,
+        bytes data,
+        uint256 gasPayment,
+        uint256 jobId,
+        address callback
+    );
+
     /// @notice Owner of the bridge (the ICP canister).
     address public immutable owner;
 
@@ -44,11 +55,7 @@ contract FrostyBridge {
         bytes32 functionId,
         bytes calldata data
     ) external payable returns (uint256 jobId) {
-        require(msg.value >= minPaymentWei, "Insufficient ETH: below minimum");
-        require(functionId != bytes32(0), "Function ID cannot be zero");
-
-        jobId = nextJobId++;
-        
+        jobId = _submitJob(functionId);
         emit FunctionInvoked(
             msg.sender,
             functionId,
@@ -56,9 +63,39 @@ contract FrostyBridge {
             msg.value,
             jobId
         );
-        
+    }
+
+    /// @notice Submit a new job and have its result delivered to `callback` once it finished.
+    /// @dev The callback transaction is paid from the gas payment and sent by `owner`,
+    /// which the callback contract should check. Deliveries may be retried, so the
+    /// callback might be invoked more than once for the same job.
+    /// @param functionId SHA-256 hash of the wasm binary to execute.
+    /// @param data Arbitrary binary payload to be passed to the function.
+    /// @param callback Contract implementing `IFrostyCallback` that receives the result.
+    /// @return jobId The unique identifier assigned to this job submission.
+    function invokeFunctionWithCallback(
+        bytes32 functionId,
+        bytes calldata data,
+        address callback
+    ) external payable returns (uint256 jobId) {
+        require(callback != address(0), "Callback cannot be zero address");
+        jobId = _submitJob(functionId);
+        emit FunctionInvokedWithCallback(
+            msg.sender,
+            functionId,
+            data,
+            msg.value,
+            jobId,
+            callback
+        );
+    }
+
+    /// @dev Checks the payment, assigns a job ID and forwards the payment to `owner`.
+    function _submitJob(bytes32 functionId) private returns (uint256 jobId) {
+        require(msg.value >= minPaymentWei, "Insufficient ETH: below minimum");
+        require(functionId != bytes32(0), "Function ID cannot be zero");
+
+        jobId = nextJobId++;
         payable(owner).transfer(msg.value);
-        
-        return jobId;
     }
 }
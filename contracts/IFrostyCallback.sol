@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.0;
+
+/// @title IFrostyCallback — Receives the results of jobs submitted with `invokeFunctionWithCallback`
+interface IFrostyCallback {
+
+    /// @notice Called by the Frosty Functions canister once a job finished.
+    /// @dev Implementations should only accept calls from the bridge `owner` and
+    /// must tolerate being called more than once for the same job.
+    /// @param jobId The ID assigned to the job by the bridge.
+    /// @param status 0 if the job completed, 1 if it failed.
+    /// @param result The bytes set by the function through `setResult`, if any.
+    function onFrostyResult(uint256 jobId, uint8 status, bytes calldata result) external;
+}
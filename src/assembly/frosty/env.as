@@ -45,3 +45,15 @@ export const CALLING_CHAIN_NAME: string = chainName(CALLING_CHAIN_ID);
 
 @external("❄️", "evm_chain_id")
 declare function __evm_chain_id(): u64;
+
+/**
+ * Stores the given data as the result of this job, which can then be
+ * read from the job by anyone. Calling it again replaces the previous
+ * result. The result must not exceed 64 KiB.
+ */
+export function setResult(data: Uint8Array): void {
+  __set_result(changetype<i32>(data.slice().buffer));
+}
+
+@external("❄️", "set_result")
+declare function __set_result(data_ptr: i32): void;
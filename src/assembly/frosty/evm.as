@@ -1,8 +1,13 @@
+import { Promise } from "./promise";
+import { SharedPromise } from "./internal/async";
+
 export enum EvmChain {
     EthereumMainnet = 1,
     EthereumSepolia = 11155111,
     ArbitrumOne = 42161,
     ArbitrumSepolia = 421614,
+    Base = 8453,
+    Optimism = 10,
     Localhost = 31337,
 }
 
@@ -15,7 +20,123 @@ export function chainName(chainId: u64): string {
     case EvmChain.EthereumSepolia: return "Ethereum Sepolia Testnet";
     case EvmChain.ArbitrumOne: return "Arbitrum One";
     case EvmChain.ArbitrumSepolia: return "Arbitrum Sepolia Testnet";
+    case EvmChain.Base: return "Base";
+    case EvmChain.Optimism: return "OP Mainnet";
     case EvmChain.Localhost: return "Localhost EVM Node";
     default: return "EVM Chain ID " + chainId.toString();
   }
 }
+
+/**
+ * Executes a read-only call (eth_call) against the contract at the given address
+ * on the given EVM chain. The Promise resolves with the raw ABI-encoded return data.
+ *
+ * Note that simulations don't make any RPC calls and always return 32 zero bytes.
+ */
+export function call(chainId: u64, address: Uint8Array, calldata: Uint8Array): Promise<ArrayBuffer> {
+  if (address.length != 20) {
+    throw new Error(`Address must be 20 bytes. Got ${address.length}`);
+  }
+  let promise = new SharedPromise();
+  evm_call(chainId, changetype<i32>(address.slice().buffer), changetype<i32>(calldata.slice().buffer), promise.id);
+  return promise;
+}
+
+@external("❄️", "evm_call")
+declare function evm_call(chainId: u64, addressPtr: i32, calldataPtr: i32, promiseId: i32): void;
+
+/**
+ * A log event emitted by an EVM contract.
+ */
+export class Log {
+  constructor(
+    public readonly address: Uint8Array,
+    public readonly blockNumber: u64,
+    public readonly transactionHash: Uint8Array,
+    public readonly logIndex: u64,
+    public readonly topics: Array<Uint8Array>,
+    public readonly data: Uint8Array,
+  ) {}
+}
+
+/**
+ * Fetches logs (eth_getLogs) emitted on the given EVM chain within the block
+ * range [fromBlock, toBlock]. At most 1000 blocks can be queried at once.
+ *
+ * The address is optional. Up to four topics can be provided, where a null
+ * topic matches any value. Fees are charged based on maxResponseBytes and the
+ * Promise is rejected if the response is larger than that.
+ *
+ * Note that simulations don't make any RPC calls and always return no logs.
+ */
+export function getLogs(
+  chainId: u64,
+  address: Uint8Array | null,
+  topics: Array<Uint8Array | null>,
+  fromBlock: u64,
+  toBlock: u64,
+  maxResponseBytes: i32 = 100_000
+): Promise<Array<Log>> {
+  if (address != null && address.length != 20) {
+    throw new Error(`Address must be 20 bytes. Got ${address.length}`);
+  }
+  if (topics.length > 4) {
+    throw new Error(`At most 4 topics are supported. Got ${topics.length}`);
+  }
+  // Topics are passed as a concatenation of 32 byte values, with all zeros as wildcard.
+  let topicsBuffer = new Uint8Array(topics.length * 32);
+  for (let i = 0; i < topics.length; i++) {
+    let topic = topics[i];
+    if (topic != null) {
+      if (topic.length != 32) {
+        throw new Error(`Topic must be 32 bytes. Got ${topic.length}`);
+      }
+      topicsBuffer.set(topic, i * 32);
+    }
+  }
+  let addressPtr = address != null ? changetype<i32>(address.slice().buffer) : 0;
+  let promise = new SharedPromise();
+  evm_get_logs(chainId, addressPtr, changetype<i32>(topicsBuffer.buffer), fromBlock, toBlock, maxResponseBytes, promise.id);
+  return promise.map<Array<Log>>(decodeLogs);
+}
+
+/**
+ * Decodes logs from the binary format returned by the host. All integers are little endian:
+ * u32 number of logs, followed by address (20), block number (u64), transaction hash (32),
+ * log index (u64), number of topics (u8), topics (32 each), data length (u32) and data.
+ */
+function decodeLogs(buffer: ArrayBuffer): Array<Log> {
+  let view = new DataView(buffer);
+  let offset = 0;
+  let count = view.getUint32(offset, true);
+  offset += 4;
+  let logs = new Array<Log>();
+  for (let i: u32 = 0; i < count; i++) {
+    let address = Uint8Array.wrap(buffer, offset, 20);
+    offset += 20;
+    let blockNumber = view.getUint64(offset, true);
+    offset += 8;
+    let transactionHash = Uint8Array.wrap(buffer, offset, 32);
+    offset += 32;
+    let logIndex = view.getUint64(offset, true);
+    offset += 8;
+    let topicCount = view.getUint8(offset);
+    offset += 1;
+    let topics = new Array<Uint8Array>();
+    for (let j: u8 = 0; j < topicCount; j++) {
+      topics.push(Uint8Array.wrap(buffer, offset, 32));
+      offset += 32;
+    }
+    let dataLength = view.getUint32(offset, true) as i32;
+    offset += 4;
+    let data = Uint8Array.wrap(buffer, offset, dataLength);
+    offset += dataLength;
+    logs.push(new Log(address, blockNumber, transactionHash, logIndex, topics, data));
+  }
+  return logs;
+}
+
+@external("❄️", "evm_get_logs")
+declare function evm_get_logs(
+  chainId: u64, addressPtr: i32, topicsPtr: i32, fromBlock: u64, toBlock: u64, maxResponseBytes: i32, promiseId: i32
+): void;
@@ -3,3 +3,4 @@ export { hex } from "frosty/hex";
 export { Promise } from "frosty/promise";
 export { verifiableRandomness } from "frosty/random";
 export { Signer } from "frosty/signer";
+export { delay } from "frosty/timers";
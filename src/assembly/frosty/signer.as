@@ -56,6 +56,39 @@ export class Signer {
     ));
   }
 
+  /**
+   * Sends an EIP-1559 transaction from this signer's address on the calling chain.
+   * The Promise resolves with the 32 byte transaction hash once the transaction
+   * was broadcast. Nonce and priority fee are determined by the host.
+   *
+   * The value is optional and needs to be a 32 byte big-endian integer in wei.
+   * Gas is paid on-chain by this signer's wallet, which needs to hold at least
+   * gasLimit * maxFeePerGas. The transaction is rejected if the current network
+   * fees exceed maxFeePerGas.
+   */
+  sendTransaction(
+    to: Uint8Array,
+    data: Uint8Array | null,
+    value: Uint8Array | null,
+    gasLimit: u64,
+    maxFeePerGas: u64
+  ): Promise<Uint8Array> {
+    if (to.length != 20) {
+      throw new Error(`Address must be 20 bytes. Got ${to.length}`);
+    }
+    if (value != null && value.length != 32) {
+      throw new Error(`Value must be 32 bytes. Got ${value.length}`);
+    }
+    let toPtr = changetype<i32>(to.slice().buffer);
+    let dataPtr = data != null ? changetype<i32>(data.slice().buffer) : 0;
+    let valuePtr = value != null ? changetype<i32>(value.slice().buffer) : 0;
+    let promise = new SharedPromise();
+    signer_send_transaction(
+      this.signerType, changetype<i32>(this.derivationPath), toPtr, valuePtr, dataPtr, gasLimit, maxFeePerGas, promise.id
+    );
+    return promise.map<Uint8Array>(txHash => Uint8Array.wrap(txHash));
+  }
+
   /**
    * Creates a signer for the caller of the function. This signer is shared
    * among all Frosty Functions, therefore callers who have assets controlled
@@ -94,3 +127,8 @@ declare function signer_eth_address(signerType: i32, derivationPtr: i32, bufferP
 
 @external("❄️", "sign_with_ecdsa")
 declare function sign_with_ecdsa(signerType: i32, derivationPtr: i32, messagePtr: i32, promiseId: i32): void;
+
+@external("❄️", "signer_send_transaction")
+declare function signer_send_transaction(
+  signerType: i32, derivationPtr: i32, toPtr: i32, valuePtr: i32, dataPtr: i32, gasLimit: u64, maxFeePerGas: u64, promiseId: i32
+): void;
@@ -0,0 +1,20 @@
+import { DONE, Done, Promise } from "./promise";
+import { SharedPromise } from "./internal/async";
+
+/**
+ * Returns a Promise that resolves after the given number of milliseconds.
+ * The delay can be at most 24 hours.
+ *
+ * The job is suspended while waiting and does not consume any instructions.
+ * If the Frosty canister is upgraded while waiting, the job fails with a
+ * SystemError and the unused gas is refunded. In simulations, timers resolve
+ * right away.
+ */
+export function delay(milliseconds: u64): Promise<Done> {
+  let promise = new SharedPromise();
+  set_timer(milliseconds, promise.id);
+  return promise.map<Done>(_ => DONE);
+}
+
+@external("❄️", "set_timer")
+declare function set_timer(delayMs: u64, promiseId: i32): void;
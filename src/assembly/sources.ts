@@ -7,6 +7,7 @@ import hex from './frosty/hex.as'
 import promise from './frosty/promise.as'
 import random from './frosty/random.as'
 import signer from './frosty/signer.as'
+import timers from './frosty/timers.as'
 
 import internal_async from './frosty/internal/async.as'
 
@@ -25,6 +26,7 @@ export const FROSTY_SOURCES = new Map<string, string>([
   ['frosty/promise', promise],
   ['frosty/random', random],
   ['frosty/signer', signer],
+  ['frosty/timers', timers],
   ['frosty/internal/async', internal_async]
 ]);
 
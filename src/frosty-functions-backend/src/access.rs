@@ -0,0 +1,127 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::{is_controller, msg_caller};
+use serde::Deserialize;
+
+use crate::storage::{append_role_change, delete_role_grant, has_role_grant, store_role_grant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum Role {
+    /// Can grant and revoke all roles. Controllers of the canister are always owners.
+    Owner,
+    /// Manages pricing, fees and the chain registry. Includes the Deployer and
+    /// IndexerOperator roles and can grant them to others.
+    Admin,
+    /// Can deploy functions. Granting this role to the anonymous principal
+    /// opens deployment to everyone.
+    Deployer,
+    /// Manages indexing and RPC consensus.
+    IndexerOperator,
+}
+
+impl Role {
+    /// Role required to grant or revoke this role.
+    fn manager(&self) -> Role {
+        match self {
+            Role::Owner | Role::Admin => Role::Owner,
+            Role::Deployer | Role::IndexerOperator => Role::Admin,
+        }
+    }
+
+    /// Roles that include this role.
+    fn implied_by(&self) -> &'static [Role] {
+        match self {
+            Role::Owner => &[Role::Owner],
+            Role::Admin => &[Role::Owner, Role::Admin],
+            Role::Deployer => &[Role::Owner, Role::Admin, Role::Deployer],
+            Role::IndexerOperator => &[Role::Owner, Role::Admin, Role::IndexerOperator],
+        }
+    }
+}
+
+/// A role held by a principal.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct RoleGrant {
+    pub principal: Principal,
+    pub role: Role,
+}
+
+/// Entry of the audit log of role changes.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoleChange {
+    /// Timestamp of the change (Unix nanoseconds).
+    pub timestamp: u64,
+    /// Principal that made the change.
+    pub changed_by: Principal,
+    pub grant: RoleGrant,
+    /// Whether the role was granted or revoked.
+    pub granted: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    /// Roles granted when the canister is installed or upgraded.
+    pub roles: Vec<RoleGrant>,
+}
+
+/// Grants the roles from the init or upgrade arguments.
+pub fn init(args: Option<InitArgs>) {
+    for grant in args.map(|args| args.roles).unwrap_or_default() {
+        if let Err(err) = store_grant(grant) {
+            ic_cdk::trap(&err);
+        }
+    }
+}
+
+/// Whether the given principal holds the given role, directly or through a
+/// role that includes it.
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    if is_controller(principal) {
+        return true;
+    }
+    role.implied_by().iter().any(|role| {
+        has_role_grant(&RoleGrant { principal: *principal, role: *role })
+    }) || (role == Role::Deployer && has_role_grant(&RoleGrant { principal: Principal::anonymous(), role }))
+}
+
+/// Returns an error if the caller does not hold the given role.
+pub fn ensure_role(role: Role) -> Result<(), String> {
+    let caller = msg_caller();
+    if !has_role(&caller, role) {
+        return Err(format!("Caller {} does not have the {:?} role", caller, role));
+    }
+    Ok(())
+}
+
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+    ensure_role(role.manager())?;
+    store_grant(RoleGrant { principal, role })
+}
+
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), String> {
+    ensure_role(role.manager())?;
+    let grant = RoleGrant { principal, role };
+    if !delete_role_grant(&grant) {
+        return Err(format!("{} does not have the {:?} role", principal, role));
+    }
+    log_change(grant, false);
+    Ok(())
+}
+
+fn store_grant(grant: RoleGrant) -> Result<(), String> {
+    if grant.principal == Principal::anonymous() && grant.role != Role::Deployer {
+        return Err("Only the Deployer role can be granted to the anonymous principal".to_string());
+    }
+    if store_role_grant(grant.clone()) {
+        log_change(grant, true);
+    }
+    Ok(())
+}
+
+fn log_change(grant: RoleGrant, granted: bool) {
+    append_role_change(&RoleChange {
+        timestamp: ic_cdk::api::time(),
+        changed_by: msg_caller(),
+        grant,
+        granted,
+    });
+}
@@ -1,51 +1,83 @@
 
-use candid::{CandidType};
+use candid::{CandidType, Principal};
 use evm_rpc_types::{Hex20};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Hash, Ord, PartialOrd, PartialEq, Eq, CandidType, Deserialize)]
 pub enum Chain {
-    Evm(EvmChain)
+    Evm(EvmChain),
+    /// Direct invocations by canisters on the Internet Computer.
+    Icp,
 }
 
 impl Chain {
     pub fn is_testnet(&self) -> bool {
         match self {
             Chain::Evm(evm_chain) => evm_chain.is_testnet(),
+            Chain::Icp => false,
         }
     }
 }
 
 #[derive(Debug, Clone, Hash, Ord, PartialOrd, PartialEq, Eq, CandidType, Deserialize)]
 pub enum EvmChain {
+    EthereumMainnet,
+    EthereumSepolia,
     ArbitrumOne,
     ArbitrumSepolia,
-    Localhost
+    Base,
+    Optimism,
+    Localhost,
+    /// Chain with the given EIP-155 chain ID, configured in the chain registry.
+    Custom(u64),
 }
 
 impl EvmChain {
     pub fn chain_id(&self) -> u64 {
         match self {
+            EvmChain::EthereumMainnet => 1,
+            EvmChain::EthereumSepolia => 11155111,
             EvmChain::ArbitrumOne => 42161,
             EvmChain::ArbitrumSepolia => 421614,
+            EvmChain::Base => 8453,
+            EvmChain::Optimism => 10,
             EvmChain::Localhost => 31337,
+            EvmChain::Custom(chain_id) => *chain_id,
         }
     }
 
-    pub fn is_testnet(&self) -> bool {
-        match self {
-            EvmChain::ArbitrumSepolia => true,
-            EvmChain::ArbitrumOne => false,
-            EvmChain::Localhost => true,
+    /// Looks up the chain with the given EIP-155 chain ID.
+    pub fn from_chain_id(chain_id: u64) -> Option<EvmChain> {
+        match chain_id {
+            1 => Some(EvmChain::EthereumMainnet),
+            11155111 => Some(EvmChain::EthereumSepolia),
+            42161 => Some(EvmChain::ArbitrumOne),
+            421614 => Some(EvmChain::ArbitrumSepolia),
+            8453 => Some(EvmChain::Base),
+            10 => Some(EvmChain::Optimism),
+            31337 => Some(EvmChain::Localhost),
+            _ => Some(EvmChain::Custom(chain_id))
+                .filter(|chain| crate::registry::chain_config(chain).is_some()),
         }
     }
+
+    /// Number of blocks after which a block is considered final. If None,
+    /// the chain's `finalized` block tag is used instead.
+    pub fn finality_depth(&self) -> Option<u64> {
+        crate::registry::chain_config(self).and_then(|config| config.finality_depth)
+    }
+
+    pub fn is_testnet(&self) -> bool {
+        crate::registry::chain_config(self).is_some_and(|config| config.is_testnet)
+    }
 }
 
 /// A generic address type that can represent addresses from different blockchain types.
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize)]
 pub enum Address {
-    EvmAddress(Hex20)
-} 
+    EvmAddress(Hex20),
+    Principal(Principal),
+}
 
 /// A caller identified by chain and address.
 #[derive(Debug, Clone, CandidType, Deserialize)]
@@ -63,11 +95,17 @@ impl Into<Vec<u8>> for Caller {
               v.push(0u8);  // Chain type: EVM
               v.extend_from_slice(&chain_id.to_be_bytes());
             }
+            crate::chain::Chain::Icp => {
+              v.push(1u8);  // Chain type: ICP
+            }
         }
         match self.address {
             crate::chain::Address::EvmAddress(evm_address) => {
                 v.extend_from_slice(evm_address.as_ref());
             }
+            crate::chain::Address::Principal(principal) => {
+                v.extend_from_slice(principal.as_slice());
+            }
         }
         v
     }
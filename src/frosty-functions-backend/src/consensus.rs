@@ -0,0 +1,195 @@
+use alloy::primitives::{B256, keccak256};
+use alloy::rpc::types::{Filter, Log};
+use candid::CandidType;
+use futures::future::join_all;
+use serde::Deserialize;
+
+use crate::chain::EvmChain;
+use crate::evm::{block_hash_from, encode_logs, finalized_block_number_from, get_logs_from, latest_block_number_from, rpc_services};
+use crate::storage::{append_consensus_audit, get_consensus_threshold, store_consensus_threshold};
+
+/// Outcome of querying multiple RPC providers for the same logs. Only recorded
+/// if the providers did not all agree.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ConsensusAudit {
+    pub chain: EvmChain,
+    pub from_block: u64,
+    pub to_block: u64,
+    // Timestamp of the query (Unix nanoseconds).
+    pub timestamp: u64,
+    // Number of identical responses required.
+    pub threshold: u32,
+    pub consensus_reached: bool,
+    pub responses: Vec<ProviderResponse>,
+}
+
+/// Summary of the response of a single RPC provider.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ProviderResponse {
+    pub provider: String,
+    // Number of logs returned, if the call succeeded.
+    pub log_count: Option<u64>,
+    // Keccak256 hash of the encoded logs, if the call succeeded.
+    pub digest: Option<Vec<u8>>,
+    pub error: Option<String>,
+}
+
+/// Number of identical responses required if not configured otherwise: A majority
+/// of the configured providers. Note that with two providers this requires both,
+/// so chains should be configured with at least three to tolerate an outage.
+pub fn default_threshold(chain: &EvmChain) -> u32 {
+    rpc_services(chain).len() as u32 / 2 + 1
+}
+
+/// Returns the threshold for the given chain. A configured threshold is capped at
+/// the current number of providers, which may have shrunk since it was set.
+pub fn threshold(chain: &EvmChain) -> u32 {
+    let providers = rpc_services(chain).len() as u32;
+    get_consensus_threshold(chain)
+        .unwrap_or_else(|| default_threshold(chain))
+        .min(providers)
+        .max(1)
+}
+
+/// Sets the number of identical responses required for the given chain.
+pub fn set_threshold(chain: EvmChain, threshold: u32) -> Result<(), String> {
+    let providers = rpc_services(&chain).len() as u32;
+    if threshold == 0 || threshold > providers {
+        return Err(format!("Threshold must be between 1 and {} for {:?}", providers, chain));
+    }
+    store_consensus_threshold(chain, threshold);
+    Ok(())
+}
+
+/// Fetches logs from all RPC providers configured for the chain and returns them
+/// only if at least `threshold` providers returned identical logs. Any disagreement
+/// is recorded in the audit log.
+pub async fn get_logs(chain: &EvmChain, filter: &Filter, from_block: u64, to_block: u64) -> Result<Vec<Log>, String> {
+    let services = rpc_services(chain);
+    let providers: Vec<String> = services.iter().map(|service| format!("{:?}", service)).collect();
+    let results = join_all(services.into_iter().map(|service| get_logs_from(service, filter))).await;
+
+    // Group responses by their canonical encoding, so that providers agree
+    // exactly if their digests in the audit log are the same.
+    let encoded: Vec<Option<Vec<u8>>> = results.iter()
+        .map(|result| result.as_ref().ok().map(|logs| canonical_encoding(logs)))
+        .collect();
+    let mut groups: Vec<(&Vec<Log>, &Vec<u8>, u32)> = Vec::new();
+    for (logs, encoding) in results.iter().flatten().zip(encoded.iter().flatten()) {
+        match groups.iter_mut().find(|(_, other, _)| *other == encoding) {
+            Some((_, _, count)) => *count += 1,
+            None => groups.push((logs, encoding, 1)),
+        }
+    }
+    let threshold = threshold(chain);
+    let best = groups.iter().max_by_key(|(_, _, count)| *count).cloned();
+    let consensus = best.filter(|(_, _, count)| *count >= threshold);
+    let unanimous = groups.len() == 1 && results.iter().all(|result| result.is_ok());
+
+    if !unanimous {
+        let responses = providers.into_iter().zip(results.iter().zip(encoded.iter()))
+            .map(|(provider, result)| match result {
+                (Ok(logs), Some(encoding)) => ProviderResponse {
+                    provider,
+                    log_count: Some(logs.len() as u64),
+                    digest: Some(keccak256(encoding).to_vec()),
+                    error: None,
+                },
+                (result, _) => ProviderResponse {
+                    provider,
+                    log_count: None,
+                    digest: None,
+                    error: result.as_ref().err().cloned(),
+                },
+            })
+            .collect();
+        ic_cdk::println!("RPC providers disagree on logs for blocks {from_block}..={to_block} on chain {chain:?}");
+        append_consensus_audit(&ConsensusAudit {
+            chain: chain.clone(),
+            from_block,
+            to_block,
+            timestamp: ic_cdk::api::time(),
+            threshold,
+            consensus_reached: consensus.is_some(),
+            responses,
+        });
+    }
+
+    match consensus {
+        Some((logs, _, _)) => Ok(logs.clone()),
+        None => Err(format!(
+            "No consensus between RPC providers: {} of {} required responses agree",
+            best.map(|(_, _, count)| count).unwrap_or(0), threshold
+        )),
+    }
+}
+
+/// Returns the most recent block that at least `threshold` providers have seen.
+/// Providers are rarely in sync, so unlike for logs their answers don't need to
+/// be identical.
+pub async fn latest_block_number(chain: &EvmChain) -> Result<u64, String> {
+    let services = rpc_services(chain);
+    let results = join_all(services.into_iter().map(latest_block_number_from)).await;
+    agreed_block_number(chain, results)
+        .map_err(|e| format!("Failed to fetch latest block number: {}", e))
+}
+
+/// Returns the most recent finalized block that at least `threshold` providers
+/// have seen. For chains with a fixed finality depth, it is derived from the
+/// latest block.
+pub async fn finalized_block_number(chain: &EvmChain) -> Result<u64, String> {
+    if let Some(depth) = chain.finality_depth() {
+        return Ok(latest_block_number(chain).await?.saturating_sub(depth));
+    }
+    let services = rpc_services(chain);
+    let results = join_all(services.into_iter().map(finalized_block_number_from)).await;
+    agreed_block_number(chain, results)
+        .map_err(|e| format!("Failed to fetch finalized block number: {}", e))
+}
+
+/// Returns the hash of the canonical block with the given number, if at least
+/// `threshold` providers return the same hash.
+pub async fn block_hash(chain: &EvmChain, block_number: u64) -> Result<B256, String> {
+    let services = rpc_services(chain);
+    let results = join_all(services.into_iter().map(|service| block_hash_from(service, block_number))).await;
+    let mut groups: Vec<(B256, u32)> = Vec::new();
+    for hash in results.iter().flatten() {
+        match groups.iter_mut().find(|(other, _)| other == hash) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((*hash, 1)),
+        }
+    }
+    let threshold = threshold(chain);
+    let best = groups.into_iter().max_by_key(|(_, count)| *count);
+    match best {
+        Some((hash, count)) if count >= threshold => Ok(hash),
+        best => Err(format!(
+            "No consensus between RPC providers on the hash of block {}: {} of {} required responses agree",
+            block_number, best.map(|(_, count)| count).unwrap_or(0), threshold
+        )),
+    }
+}
+
+/// The highest block number reported or exceeded by at least `threshold`
+/// providers.
+fn agreed_block_number(chain: &EvmChain, results: Vec<Result<u64, String>>) -> Result<u64, String> {
+    let threshold = threshold(chain);
+    let mut numbers: Vec<u64> = results.iter().flatten().copied().collect();
+    numbers.sort_unstable_by(|a, b| b.cmp(a));
+    numbers.get(threshold as usize - 1).copied().ok_or_else(|| {
+        let errors: Vec<&String> = results.iter().filter_map(|result| result.as_ref().err()).collect();
+        format!("{} of {} required providers responded: {:?}", numbers.len(), threshold, errors)
+    })
+}
+
+/// Encoding that providers must agree on: The logs as passed to functions (see
+/// `evm::encode_logs`), followed by the block hash of each log. The block hash
+/// is not part of the format understood by functions, but jobs are created
+/// from it, so providers on different forks must not agree.
+fn canonical_encoding(logs: &[Log]) -> Vec<u8> {
+    let mut out = encode_logs(logs);
+    for log in logs {
+        out.extend_from_slice(log.block_hash.unwrap_or_default().as_slice());
+    }
+    out
+}
@@ -0,0 +1,332 @@
+use std::time::Duration;
+
+use alloy::primitives::{Address, B256, U256};
+use alloy::rpc::types::TransactionReceipt;
+use alloy::sol_types::SolCall;
+use candid::{CandidType, Nat};
+use evm_rpc_types::{Hex20, Hex32};
+use serde::Deserialize;
+
+use crate::chain::{Chain, EvmChain};
+use crate::evm::IFrostyCallback::onFrostyResultCall;
+use crate::evm::TransactionParams;
+use crate::outbox::{Outgoing, enqueue, enqueue_after};
+use crate::refund::queue_refund;
+use crate::runtime::{Job, JobRequest, JobStatus};
+use crate::signer::{Signer, ThresholdSigner};
+use crate::storage::{get_job, update_job};
+
+/// Gas limit of callback transactions. Callbacks that need more gas revert.
+const CALLBACK_GAS_LIMIT: u64 = 500_000;
+
+/// Number of transactions sent before giving up on a delivery.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Delay before the first retry, doubled for every further retry.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often to check whether a callback transaction was included.
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Time after which a callback transaction that was not included is replaced.
+const INCLUSION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Increase of the max fee per gas for replacement transactions. Nodes only
+/// accept replacements that pay at least 10% more.
+const REPLACEMENT_FEE_BUMP_PERCENT: u128 = 15;
+
+/// Status codes passed to `IFrostyCallback.onFrostyResult`.
+const STATUS_COMPLETED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Delivery of a job result to the callback contract requested by the caller.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Delivery {
+    /// Contract implementing `IFrostyCallback`.
+    pub callback: Hex20,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum DeliveryStatus {
+    /// A callback transaction is in flight or a retry is scheduled.
+    Pending,
+    /// The callback transaction was included and did not revert.
+    Delivered,
+    /// The callback reverted or all attempts failed.
+    Failed(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryAttempt {
+    /// Timestamp of the attempt (Unix nanoseconds).
+    pub timestamp: u64,
+    /// Hash of the callback transaction, if it was broadcast.
+    pub tx_hash: Option<Hex32>,
+    /// Nonce of the callback transaction. Transactions that were not included
+    /// are replaced using the same nonce, so that at most one callback executes.
+    pub nonce: Option<u64>,
+    /// Fee charged for the transaction. The maximum fee is reserved when the
+    /// transaction is sent and released once it was replaced. The transaction
+    /// that got included is charged its actual fee.
+    pub fee: u64,
+    pub receipt: Option<DeliveryReceipt>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryReceipt {
+    pub block_number: Option<u64>,
+    pub gas_used: u64,
+    pub effective_gas_price: Nat,
+    /// Whether the callback succeeded, i.e. did not revert.
+    pub success: bool,
+}
+
+/// Queues the delivery of the result of a finished job. The remaining gas is
+/// refunded once the delivery succeeded or failed for good.
+pub fn queue_delivery(request: &JobRequest, callback: Hex20) {
+    let delivery = Delivery { callback, status: DeliveryStatus::Pending, attempts: Vec::new() };
+    update_job(request, |job| job.delivery = Some(delivery));
+    enqueue(Outgoing::Delivery(request.clone()));
+}
+
+/// Processes a pending delivery. Called by the outbox, which calls it again
+/// after a delay until the delivery succeeded or failed for good: Checks whether
+/// a callback transaction sent earlier was included, and otherwise sends a new
+/// one once the previous one timed out.
+pub(crate) async fn send_callback(request: &JobRequest) {
+    let Some(job) = request.on_chain_id.clone().and_then(|id| get_job(&request.chain, id.into())) else {
+        ic_cdk::println!("ERROR: Job {:?} to deliver not found", request.on_chain_id);
+        return;
+    };
+    let Some(delivery) = job.delivery.clone().filter(|delivery| matches!(delivery.status, DeliveryStatus::Pending)) else {
+        return;
+    };
+    let Some(chain) = evm_chain(request) else {
+        give_up(request, "Callbacks are only supported on EVM chains".to_string());
+        return;
+    };
+
+    let mut unknown = false;
+    for (index, attempt) in delivery.attempts.iter().enumerate() {
+        let Some(tx_hash) = attempt.tx_hash.as_ref().filter(|_| attempt.receipt.is_none()) else {
+            continue;
+        };
+        match crate::evm::transaction_receipt(&chain, B256::from_slice(tx_hash.as_ref())).await {
+            Ok(Some(receipt)) => {
+                record_receipt(request, index, receipt);
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                ic_cdk::println!("ERROR: Failed to fetch receipt of {:?}: {}", tx_hash, err);
+                unknown = true;
+            }
+        }
+    }
+
+    // Only send another transaction if none of the earlier ones can be included
+    // without us knowing.
+    if unknown {
+        enqueue_after(Outgoing::Delivery(request.clone()), RECEIPT_POLL_INTERVAL);
+        return;
+    }
+    if let Some(last) = in_flight(&delivery) {
+        if ic_cdk::api::time() < last.timestamp + INCLUSION_TIMEOUT.as_nanos() as u64 {
+            enqueue_after(Outgoing::Delivery(request.clone()), RECEIPT_POLL_INTERVAL);
+            return;
+        }
+    }
+    if delivery.attempts.len() >= MAX_ATTEMPTS {
+        give_up(request, format!("Giving up after {} attempts", delivery.attempts.len()));
+        return;
+    }
+    send_attempt(request, &chain, &job).await;
+}
+
+/// Sends a callback transaction and records the attempt. A transaction that is
+/// still in flight is replaced, and its reservation is released.
+async fn send_attempt(request: &JobRequest, chain: &EvmChain, job: &Job) {
+    let mut attempt = DeliveryAttempt {
+        timestamp: ic_cdk::api::time(),
+        tx_hash: None,
+        nonce: None,
+        fee: 0,
+        receipt: None,
+        error: None,
+    };
+    match send_transaction(chain, job).await {
+        Ok((tx_hash, nonce, fee)) => {
+            attempt.tx_hash = Some(tx_hash.0.into());
+            attempt.nonce = Some(nonce);
+            attempt.fee = fee;
+        }
+        Err(err) => {
+            ic_cdk::println!("ERROR: Delivery for job {:?} failed: {}", request.on_chain_id, err);
+            attempt.error = Some(err);
+        }
+    }
+
+    let sent = attempt.tx_hash.is_some();
+    let attempts = update_job(request, |job| {
+        let Some(delivery) = job.delivery.as_mut() else {
+            return 0;
+        };
+        let before = delivery_fees(delivery);
+        if sent {
+            // Replaced transactions can't be included anymore, as they either
+            // share the nonce or their nonce was used by another transaction.
+            for replaced in delivery.attempts.iter_mut().filter(|attempt| attempt.tx_hash.is_some() && attempt.receipt.is_none()) {
+                replaced.fee = 0;
+                replaced.error.get_or_insert_with(|| "Replaced by a later attempt".to_string());
+            }
+        }
+        delivery.attempts.push(attempt);
+        let after = delivery_fees(delivery);
+        let attempts = delivery.attempts.len();
+        job.gas_fees = job.gas_fees - before + after;
+        attempts
+    });
+
+    let delay = match sent {
+        true => RECEIPT_POLL_INTERVAL,
+        false => INITIAL_RETRY_DELAY * 2u32.pow(attempts.saturating_sub(1) as u32),
+    };
+    enqueue_after(Outgoing::Delivery(request.clone()), delay);
+}
+
+/// Calls `onFrostyResult` on the callback contract using the canister's main
+/// EVM account. Reuses the nonce of a transaction that is still in flight, so
+/// that only one of them can be included. The maximum fee must be covered by
+/// the job's remaining gas plus the reservation of the replaced transaction.
+/// Returns the transaction hash, nonce and maximum fee.
+async fn send_transaction(chain: &EvmChain, job: &Job) -> Result<(B256, u64, u64), String> {
+    let job_id = job.request.on_chain_id.clone().ok_or("Job without ID")?;
+    let delivery = job.delivery.as_ref().ok_or("Job without delivery")?;
+    let status = match &job.status {
+        JobStatus::Completed => STATUS_COMPLETED,
+        JobStatus::Failed(_) => STATUS_FAILED,
+        status => return Err(format!("Job is not finished: {:?}", status)),
+    };
+
+    let signer = ThresholdSigner::new(vec![]);
+    let from = signer.eth_address()?;
+    let fees = crate::evm::estimate_fees(chain).await?;
+    let mut max_fee_per_gas = fees.max_fee_per_gas;
+    let mut max_priority_fee_per_gas = None;
+    let mut nonce = None;
+    if let Some(replaced) = in_flight(delivery) {
+        let replaced_nonce = replaced.nonce.ok_or("Attempt without nonce")?;
+        // If the nonce was used by another transaction in the meantime, the
+        // replaced transaction can't be included anymore and a new nonce is used.
+        if crate::evm::transaction_count(chain, from, false).await? <= replaced_nonce {
+            let replaced_max_fee_per_gas = replaced.fee as u128 / CALLBACK_GAS_LIMIT as u128;
+            max_fee_per_gas = max_fee_per_gas.max(replaced_max_fee_per_gas * (100 + REPLACEMENT_FEE_BUMP_PERCENT) / 100);
+            // The priority fee must be bumped as well, and the one of the replaced
+            // transaction is at most its max fee.
+            max_priority_fee_per_gas = Some(max_fee_per_gas);
+            nonce = Some(replaced_nonce);
+        }
+    }
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => crate::evm::transaction_count(chain, from, true).await?,
+    };
+
+    let max_fee = u64::try_from(max_fee_per_gas * CALLBACK_GAS_LIMIT as u128)
+        .map_err(|_| "Callback fee exceeds u64".to_string())?;
+    let available = job.remaining_gas() + Nat::from(in_flight(delivery).map_or(0, |attempt| attempt.fee));
+    if available < Nat::from(max_fee) {
+        return Err(format!("Remaining gas of {} does not cover the callback fee of {}", available, max_fee));
+    }
+
+    let call = onFrostyResultCall {
+        jobId: U256::from_be_bytes(job_id.into_be_bytes()),
+        status,
+        result: job.result.clone().unwrap_or_default().into(),
+    };
+    let params = TransactionParams {
+        to: Address::from_slice(delivery.callback.as_ref()),
+        value: U256::ZERO,
+        data: call.abi_encode(),
+        gas_limit: CALLBACK_GAS_LIMIT,
+        max_fee_per_gas,
+        nonce: Some(nonce),
+        max_priority_fee_per_gas,
+    };
+    let tx_hash = crate::evm::send_transaction(chain, &signer, params).await?;
+    Ok((tx_hash, nonce, max_fee))
+}
+
+/// Records the receipt of the attempt that got included and settles the
+/// delivery. The attempt is charged its actual fee and all other reservations
+/// are released. Reverted callbacks are not retried, as they would most likely
+/// revert again.
+fn record_receipt(request: &JobRequest, index: usize, receipt: TransactionReceipt) {
+    let success = receipt.status();
+    let fee = u64::try_from(receipt.gas_used * receipt.effective_gas_price).unwrap_or(u64::MAX);
+    update_job(request, |job| {
+        let Some(delivery) = job.delivery.as_mut() else {
+            return;
+        };
+        let before = delivery_fees(delivery);
+        for (i, attempt) in delivery.attempts.iter_mut().enumerate() {
+            if i == index {
+                // The fee is covered by the reservations, as replacements
+                // reserve more than the transactions they replace.
+                attempt.fee = fee.min(before);
+                attempt.error = None;
+                attempt.receipt = Some(DeliveryReceipt {
+                    block_number: receipt.block_number,
+                    gas_used: receipt.gas_used as u64,
+                    effective_gas_price: Nat::from(receipt.effective_gas_price),
+                    success,
+                });
+            } else if attempt.receipt.is_none() {
+                attempt.fee = 0;
+            }
+        }
+        delivery.status = match success {
+            true => DeliveryStatus::Delivered,
+            false => DeliveryStatus::Failed("Callback reverted".to_string()),
+        };
+        let after = delivery_fees(delivery);
+        job.gas_fees = job.gas_fees - before + after;
+    });
+    queue_refund(request);
+}
+
+/// Marks the delivery as failed and refunds the remaining gas. The reservation
+/// of a transaction that is still in flight is kept, as it might still be
+/// included.
+fn give_up(request: &JobRequest, reason: String) {
+    ic_cdk::println!("ERROR: Delivery for job {:?} failed: {}", request.on_chain_id, reason);
+    update_job(request, |job| {
+        if let Some(delivery) = job.delivery.as_mut() {
+            delivery.status = DeliveryStatus::Failed(reason);
+        }
+    });
+    queue_refund(request);
+}
+
+/// The latest broadcast transaction, unless it was included or replaced.
+/// Attempts that failed before broadcasting are skipped.
+fn in_flight(delivery: &Delivery) -> Option<&DeliveryAttempt> {
+    delivery.attempts.iter().rev()
+        .find(|attempt| attempt.tx_hash.is_some())
+        .filter(|attempt| attempt.receipt.is_none() && attempt.error.is_none())
+}
+
+/// Fees currently charged for the delivery's transactions.
+fn delivery_fees(delivery: &Delivery) -> u64 {
+    delivery.attempts.iter().map(|attempt| attempt.fee).sum()
+}
+
+fn evm_chain(request: &JobRequest) -> Option<EvmChain> {
+    match &request.chain {
+        Chain::Evm(chain) => Some(chain.clone()),
+        Chain::Icp => None,
+    }
+}
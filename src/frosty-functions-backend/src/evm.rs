@@ -1,18 +1,30 @@
+use alloy::consensus::{SignableTransaction, Signed, TxEip1559, TxEnvelope};
 use alloy::eips::BlockNumberOrTag;
+use alloy::eips::eip2718::Encodable2718;
 use alloy::primitives::Address;
+use alloy::primitives::Bytes;
+use alloy::primitives::Signature;
+use alloy::primitives::TxKind;
+use alloy::primitives::B256;
+use alloy::primitives::U256;
+use alloy::providers::utils::Eip1559Estimation;
 use alloy::providers::Provider;
 use alloy::providers::ProviderBuilder;
 use alloy::rpc::types::Filter;
 use alloy::rpc::types::Log;
+use alloy::rpc::types::TransactionInput;
+use alloy::rpc::types::TransactionReceipt;
+use alloy::rpc::types::TransactionRequest;
 use alloy::sol;
 use alloy::sol_types::SolEvent;
-use alloy::transports::icp::{L2MainnetService, RpcApi, RpcService};
+use alloy::transports::icp::{EthMainnetService, EthSepoliaService, IcpConfig, L2MainnetService, RpcApi, RpcService};
 use evm_rpc_types::Nat256;
 
 use crate::chain::Chain;
 use crate::chain::EvmChain;
-use crate::evm::FrostyBridge::FunctionInvoked;
+use crate::evm::FrostyBridge::{FunctionInvoked, FunctionInvokedWithCallback};
 use crate::runtime::JobRequest;
+use crate::signer::Signer;
 use crate::storage::create_job;
 
 sol! {
@@ -20,26 +32,33 @@ sol! {
     "../../contracts/Bridge.sol"
 }
 
+sol! {
+    "../../contracts/IFrostyCallback.sol"
+}
+
 /// Creates jobs from log events in the specified block.
 pub async fn index_block(chain: &EvmChain, block_number: u64) -> Result<Vec<JobRequest>, String> {
-    // TODO: Configure response size, use multiple providers etc.
-    let config = alloy::transports::icp::IcpConfig::new(rpc_service(&chain));
-    let provider = ProviderBuilder::new().on_icp(config);
+    index_blocks(chain, block_number, block_number).await
+}
+
+/// Creates jobs from log events in the specified (inclusive) block range.
+pub async fn index_blocks(chain: &EvmChain, from_block: u64, to_block: u64) -> Result<Vec<JobRequest>, String> {
+    let bridge = bridge_address(chain)
+        .ok_or_else(|| format!("No bridge contract deployed on {:?}", chain))?;
     let filter = Filter::new()
-        .address(bridge_address(chain))
-        .event(FrostyBridge::FunctionInvoked::SIGNATURE)
-        .from_block(BlockNumberOrTag::Number(block_number))
-        .to_block(BlockNumberOrTag::Number(block_number));
-    let job_ids = provider
-        .get_logs(&filter)
+        .address(bridge)
+        .events([FunctionInvoked::SIGNATURE, FunctionInvokedWithCallback::SIGNATURE])
+        .from_block(BlockNumberOrTag::Number(from_block))
+        .to_block(BlockNumberOrTag::Number(to_block));
+    let job_ids = crate::consensus::get_logs(chain, &filter, from_block, to_block)
         .await
-        .map_err(|e: alloy::transports::RpcError<alloy::transports::TransportErrorKind>| format!("Failed to fetch Bridge events: {}", e))?
+        .map_err(|e| format!("Failed to fetch Bridge events: {}", e))?
         .into_iter()
         // Create JobRequests from log events.
         .filter_map(|log| {
             let job = job_from_event(chain, log);
             if job.is_err() {
-                ic_cdk::println!("ERROR: Failed to parse event from block {block_number} on chain {chain:?}: {}", job.as_ref().unwrap_err());
+                ic_cdk::println!("ERROR: Failed to parse event from blocks {from_block}..={to_block} on chain {chain:?}: {}", job.as_ref().unwrap_err());
             }
             job.ok()
         })
@@ -55,19 +74,250 @@ pub async fn index_block(chain: &EvmChain, block_number: u64) -> Result<Vec<JobR
     Ok(job_ids)
 }
 
+/// Returns the number of the most recent block according to a specific RPC
+/// service. See `consensus::latest_block_number` for the number agreed on by
+/// the configured providers.
+pub async fn latest_block_number_from(service: RpcService) -> Result<u64, String> {
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(service));
+    provider
+        .get_block_number()
+        .await
+        .map_err(|e| format!("Failed to fetch latest block number: {}", e))
+}
+
+/// Returns the number of the most recent finalized block (`finalized` tag)
+/// according to a specific RPC service.
+pub async fn finalized_block_number_from(service: RpcService) -> Result<u64, String> {
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(service));
+    let block = provider
+        .get_block_by_number(BlockNumberOrTag::Finalized, false)
+        .await
+        .map_err(|e| format!("Failed to fetch finalized block: {}", e))?
+        .ok_or_else(|| "Finalized block not found".to_string())?;
+    Ok(block.header.number)
+}
+
+/// Returns the hash of the block with the given number according to a
+/// specific RPC service.
+pub async fn block_hash_from(service: RpcService, block_number: u64) -> Result<B256, String> {
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(service));
+    let block = provider
+        .get_block_by_number(BlockNumberOrTag::Number(block_number), false)
+        .await
+        .map_err(|e| format!("Failed to fetch block {}: {}", block_number, e))?
+        .ok_or_else(|| format!("Block {} not found", block_number))?;
+    Ok(block.header.hash)
+}
+
+/// Fetches all logs matching the given filter (eth_getLogs). The outcall fails
+/// if the RPC response is larger than `max_response_bytes`, which bounds the
+/// cycles paid for it.
+pub async fn get_logs(chain: &EvmChain, filter: &Filter, max_response_bytes: u64) -> Result<Vec<Log>, String> {
+    let config = icp_config(chain)?.set_max_response_size(max_response_bytes);
+    ProviderBuilder::new()
+        .on_icp(config)
+        .get_logs(filter)
+        .await
+        .map_err(|e: alloy::transports::RpcError<alloy::transports::TransportErrorKind>| e.to_string())
+}
+
+/// Fetches all logs matching the given filter from a specific RPC service.
+pub async fn get_logs_from(service: RpcService, filter: &Filter) -> Result<Vec<Log>, String> {
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(service));
+    provider
+        .get_logs(filter)
+        .await
+        .map_err(|e: alloy::transports::RpcError<alloy::transports::TransportErrorKind>| e.to_string())
+}
+
+/// Encodes logs into the binary format understood by the `frosty/evm` module.
+/// All integers are little endian:
+///
+/// ```text
+/// u32 number of logs, followed by for each log:
+///   [20] address
+///   u64  block number
+///   [32] transaction hash
+///   u64  log index
+///   u8   number of topics, followed by [32] for each topic
+///   u32  data length, followed by the data
+/// ```
+///
+/// Missing block numbers, transaction hashes or log indexes (pending logs) are
+/// encoded as zero.
+pub fn encode_logs(logs: &[Log]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(logs.len() as u32).to_le_bytes());
+    for log in logs {
+        out.extend_from_slice(log.address().as_slice());
+        out.extend_from_slice(&log.block_number.unwrap_or_default().to_le_bytes());
+        out.extend_from_slice(log.transaction_hash.unwrap_or_default().as_slice());
+        out.extend_from_slice(&log.log_index.unwrap_or_default().to_le_bytes());
+        out.push(log.topics().len() as u8);
+        for topic in log.topics() {
+            out.extend_from_slice(topic.as_slice());
+        }
+        let data = &log.data().data;
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// Executes a read-only message call (eth_call) against the latest block and
+/// returns the raw return data.
+pub async fn eth_call(chain: &EvmChain, to: Address, calldata: Vec<u8>) -> Result<Vec<u8>, String> {
+    let provider = ProviderBuilder::new().on_icp(icp_config(chain)?);
+    let tx = TransactionRequest::default()
+        .to(to)
+        .input(TransactionInput::new(Bytes::from(calldata)));
+    let result = provider
+        .call(&tx)
+        .await
+        .map_err(|e| format!("eth_call failed: {}", e))?;
+    Ok(result.to_vec())
+}
+
+/// Parameters for an EIP-1559 transaction sent on behalf of a function.
+pub struct TransactionParams {
+    pub to: Address,
+    pub value: U256,
+    pub data: Vec<u8>,
+    pub gas_limit: u64,
+    /// Upper bound for the fee per gas that the function is willing to pay.
+    pub max_fee_per_gas: u128,
+    /// Nonce to use, e.g. to replace a pending transaction. If None, the next
+    /// nonce of the sender including pending transactions is used.
+    pub nonce: Option<u64>,
+    /// Priority fee per gas, capped at `max_fee_per_gas`. If None, the estimated
+    /// priority fee is used.
+    pub max_priority_fee_per_gas: Option<u128>,
+}
+
+/// Builds an EIP-1559 transaction from the signer's address, signs it with the
+/// given signer and broadcasts it. Returns the transaction hash.
+pub async fn send_transaction(chain: &EvmChain, signer: &dyn Signer, params: TransactionParams) -> Result<B256, String> {
+    let (_, raw_tx) = build_transaction(chain, signer, params).await?;
+    send_raw_transaction(chain, raw_tx).await
+}
+
+/// Builds an EIP-1559 transaction from the signer's address and signs it with
+/// the given signer. Returns the transaction hash and the encoded transaction,
+/// so that the hash can be recorded before broadcasting.
+pub async fn build_transaction(chain: &EvmChain, signer: &dyn Signer, params: TransactionParams) -> Result<(B256, Vec<u8>), String> {
+    let from = signer.eth_address()?;
+    let nonce = match params.nonce {
+        Some(nonce) => nonce,
+        None => transaction_count(chain, from, true).await?,
+    };
+    let fees = estimate_fees(chain).await?;
+    if fees.max_fee_per_gas > params.max_fee_per_gas {
+        return Err(format!(
+            "Estimated max fee per gas of {} exceeds the limit of {}",
+            fees.max_fee_per_gas, params.max_fee_per_gas
+        ));
+    }
+
+    let tx = TxEip1559 {
+        chain_id: chain.chain_id(),
+        nonce,
+        gas_limit: params.gas_limit.into(),
+        max_fee_per_gas: params.max_fee_per_gas,
+        max_priority_fee_per_gas: params.max_priority_fee_per_gas.unwrap_or(fees.max_priority_fee_per_gas).min(params.max_fee_per_gas),
+        to: TxKind::Call(params.to),
+        value: params.value,
+        access_list: Default::default(),
+        input: Bytes::from(params.data),
+    };
+    let signed = sign_transaction(signer, from, tx).await?;
+    let tx_hash = *signed.hash();
+    Ok((tx_hash, TxEnvelope::Eip1559(signed).encoded_2718()))
+}
+
+/// Broadcasts a signed transaction (eth_sendRawTransaction). Returns the
+/// transaction hash.
+pub async fn send_raw_transaction(chain: &EvmChain, raw_tx: Vec<u8>) -> Result<B256, String> {
+    let provider = ProviderBuilder::new().on_icp(icp_config(chain)?);
+    let pending = provider
+        .send_raw_transaction(&raw_tx)
+        .await
+        .map_err(|e| format!("Failed to send transaction: {}", e))?;
+    Ok(*pending.tx_hash())
+}
+
+/// Returns the number of transactions sent from the given address, i.e. the
+/// next nonce. Includes transactions in the mempool if `pending` is set.
+pub async fn transaction_count(chain: &EvmChain, address: Address, pending: bool) -> Result<u64, String> {
+    let provider = ProviderBuilder::new().on_icp(icp_config(chain)?);
+    let count = provider.get_transaction_count(address);
+    let count = if pending { count.pending() } else { count.latest() };
+    count.await.map_err(|e| format!("Failed to fetch nonce: {}", e))
+}
+
+/// Fetches the receipt of the given transaction. Returns None if the transaction
+/// has not been included in a block yet.
+pub async fn transaction_receipt(chain: &EvmChain, tx_hash: B256) -> Result<Option<TransactionReceipt>, String> {
+    let provider = ProviderBuilder::new().on_icp(icp_config(chain)?);
+    provider
+        .get_transaction_receipt(tx_hash)
+        .await
+        .map_err(|e| format!("Failed to fetch transaction receipt: {}", e))
+}
+
+/// Estimates the current EIP-1559 fees per gas.
+pub async fn estimate_fees(chain: &EvmChain) -> Result<Eip1559Estimation, String> {
+    let provider = ProviderBuilder::new().on_icp(icp_config(chain)?);
+    provider
+        .estimate_eip1559_fees(None)
+        .await
+        .map_err(|e| format!("Failed to estimate fees: {}", e))
+}
+
+/// Signs the transaction with the threshold signer. The management canister
+/// only returns r and s, so the recovery ID is determined by recovering the
+/// signer's address from the signature.
+async fn sign_transaction(signer: &dyn Signer, from: Address, tx: TxEip1559) -> Result<Signed<TxEip1559>, String> {
+    let hash = tx.signature_hash();
+    let signature = signer.sign_with_ecdsa(hash.to_vec()).await?;
+    let signature = [false, true]
+        .into_iter()
+        .filter_map(|parity| Signature::from_bytes_and_parity(&signature, parity).ok())
+        .find(|signature| signature.recover_address_from_prehash(&hash).ok() == Some(from))
+        .ok_or_else(|| "Failed to determine recovery ID of signature".to_string())?;
+    Ok(tx.into_signed(signature))
+}
+
 fn job_from_event(chain: &EvmChain, event: Log) -> Result<JobRequest, String> {
-    let event = event.log_decode::<FunctionInvoked>()
-        .map_err(|err| format!("Failed to decode log event {}", err))?;
+    // Both events carry the same fields, except for the callback.
+    let (invoked, callback) = if event.topics().first() == Some(&FunctionInvokedWithCallback::SIGNATURE_HASH) {
+        let decoded = event.log_decode::<FunctionInvokedWithCallback>()
+            .map_err(|err| format!("Failed to decode log event {}", err))?
+            .inner.data;
+        let invoked = FunctionInvoked {
+            caller: decoded.caller,
+            functionId: decoded.functionId,
+            data: decoded.data,
+            gasPayment: decoded.gasPayment,
+            jobId: decoded.jobId,
+        };
+        (invoked, Some(decoded.callback))
+    } else {
+        let invoked = event.log_decode::<FunctionInvoked>()
+            .map_err(|err| format!("Failed to decode log event {}", err))?
+            .inner.data;
+        (invoked, None)
+    };
     let job = JobRequest {
         chain: Chain::Evm(chain.clone()),
         block_hash: event.block_hash.map(|v| v.0.into()),
         block_number: event.block_number,
         transaction_hash: event.transaction_hash.map(|v| v.0.into()),
-        on_chain_id: Some(Nat256::from_be_bytes(event.inner.jobId.to_be_bytes())),
-        caller: crate::chain::Address::EvmAddress(event.inner.caller.0.0.into()),
-        function_hash: event.inner.functionId.0.to_vec(),
-        data: event.inner.data.data.to_vec(),
-        gas_payment: Nat256::from_be_bytes(event.inner.gasPayment.to_be_bytes()),
+        on_chain_id: Some(Nat256::from_be_bytes(invoked.jobId.to_be_bytes())),
+        caller: crate::chain::Address::EvmAddress(invoked.caller.0.0.into()),
+        function_hash: invoked.functionId.0.to_vec(),
+        data: invoked.data.to_vec(),
+        gas_payment: Nat256::from_be_bytes(invoked.gasPayment.to_be_bytes()),
+        callback: callback.map(|address| address.0.0.into()),
     };
     if job.block_hash.is_none() || job.block_number.is_none() || job.transaction_hash.is_none() {
         return Err("Missing block hash, block number or transaction hash in event".to_string());
@@ -75,26 +325,67 @@ fn job_from_event(chain: &EvmChain, event: Log) -> Result<JobRequest, String> {
     Ok(job)
 }
 
-fn rpc_service(evm_chain: &EvmChain) -> RpcService {
-    // TODO: Fetch from multiple providers to ensure consistency.
+fn icp_config(evm_chain: &EvmChain) -> Result<IcpConfig, String> {
+    Ok(IcpConfig::new(rpc_service(evm_chain)?))
+}
+
+/// RPC service used for calls that don't require consensus between providers:
+/// Calls on behalf of functions, which are charged per call, and transactions
+/// sent by the canister itself. Jobs are only created from data that the
+/// providers agree on (see `consensus`).
+fn rpc_service(evm_chain: &EvmChain) -> Result<RpcService, String> {
+    rpc_services(evm_chain).into_iter().next()
+        .ok_or_else(|| format!("No RPC endpoints configured for {:?}", evm_chain))
+}
+
+/// All RPC services configured for the given chain. Used for verifying Bridge
+/// events and the blocks they were emitted in, which requires consensus between
+/// multiple providers. Endpoints from
+/// the chain registry take precedence over the built-in providers.
+pub fn rpc_services(evm_chain: &EvmChain) -> Vec<RpcService> {
+    let rpc_urls = crate::registry::chain_config(evm_chain)
+        .map(|config| config.rpc_urls)
+        .unwrap_or_default();
+    if !rpc_urls.is_empty() {
+        return rpc_urls.into_iter()
+            .map(|url| RpcService::Custom(RpcApi { url, headers: None }))
+            .collect();
+    }
     match evm_chain {
-        EvmChain::Localhost => RpcService::Custom(RpcApi {
-            url: "http://127.0.0.1:8545".to_string(),
-            headers: None,
-        }),
-        EvmChain::ArbitrumSepolia => RpcService::Custom(RpcApi {
-            url: "https://arbitrum-sepolia-rpc.publicnode.com".to_string(),
-            headers: None,
-        }),
-        EvmChain::ArbitrumOne => RpcService::ArbitrumOne(L2MainnetService::Alchemy)
+        EvmChain::ArbitrumOne => vec![
+            RpcService::ArbitrumOne(L2MainnetService::Alchemy),
+            RpcService::ArbitrumOne(L2MainnetService::Ankr),
+            RpcService::ArbitrumOne(L2MainnetService::PublicNode),
+        ],
+        EvmChain::EthereumMainnet => vec![
+            RpcService::EthMainnet(EthMainnetService::Alchemy),
+            RpcService::EthMainnet(EthMainnetService::Ankr),
+            RpcService::EthMainnet(EthMainnetService::PublicNode),
+        ],
+        EvmChain::EthereumSepolia => vec![
+            RpcService::EthSepolia(EthSepoliaService::Alchemy),
+            RpcService::EthSepolia(EthSepoliaService::Ankr),
+            RpcService::EthSepolia(EthSepoliaService::PublicNode),
+        ],
+        EvmChain::Base => vec![
+            RpcService::BaseMainnet(L2MainnetService::Alchemy),
+            RpcService::BaseMainnet(L2MainnetService::Ankr),
+            RpcService::BaseMainnet(L2MainnetService::PublicNode),
+        ],
+        EvmChain::Optimism => vec![
+            RpcService::OptimismMainnet(L2MainnetService::Alchemy),
+            RpcService::OptimismMainnet(L2MainnetService::Ankr),
+            RpcService::OptimismMainnet(L2MainnetService::PublicNode),
+        ],
+        // These use the endpoints of their built-in registry entry.
+        EvmChain::ArbitrumSepolia | EvmChain::Localhost | EvmChain::Custom(_) => Vec::new(),
     }
 }
 
-// TODO: Move this into a config file. Maybe make it shareable with frontend as well?
-fn bridge_address(chain: &EvmChain) -> Address{
-    match chain {
-        EvmChain::ArbitrumOne => "0xe712A7e50abA019A6d225584583b09C4265B037B",
-        EvmChain::ArbitrumSepolia => "0xcAcbb4E46F2a68e3d178Fb98dCaCe59d12d54CBc",
-        EvmChain::Localhost => "0x9fE46736679d2D9a65F0992F2272dE9f3c7fa6e0"
-    }.parse().unwrap()
+/// Address of the Bridge contract on the given chain, or None if it hasn't been
+/// deployed there yet.
+fn bridge_address(chain: &EvmChain) -> Option<Address> {
+    crate::registry::chain_config(chain)
+        .and_then(|config| config.bridge_address)
+        .map(|address| Address::from_slice(address.as_ref()))
 }
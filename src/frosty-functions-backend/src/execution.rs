@@ -1,19 +1,66 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
 use std::time::Duration;
 
 use alloy::signers::icp::IcpSigner;
 use candid::Nat;
+use evm_rpc_types::Nat256;
 use futures::StreamExt;
+use futures::channel::oneshot;
 use futures::stream::FuturesUnordered;
 use ic_cdk_timers::set_timer;
 
-use crate::runtime::{Commit, JobRequest, JobStatus, RuntimeEnvironment};
-use crate::runtime::{Execution};
-use crate::storage::{get_function, update_job_status};
+use crate::chain::{Address, Chain};
+use crate::pricing::FeeSchedule;
+use crate::delivery::queue_delivery;
+use crate::refund::queue_refund;
+use crate::runtime::{Commit, FailureReason, JobRequest, JobStatus, JobStatusKind, RuntimeEnvironment};
+use crate::runtime::{DEFAULT_MEMORY_LIMIT, Execution, cached_module};
+use crate::repository::FunctionId;
+use crate::storage::{create_direct_job, get_function, list_jobs_by_status, update_job_status};
+
+/// Creates and schedules a job for a direct invocation by the calling canister.
+/// The attached cycles are accepted as gas payment, and unused cycles are
+/// deposited back once the job finished. Returns the job ID on `Chain::Icp`.
+pub fn invoke_function(function_id: FunctionId, data: Vec<u8>) -> Result<Nat, String> {
+    if get_function(function_id.clone()).is_none() {
+        return Err("Function not found".to_string());
+    }
+    let min_payment = crate::pricing::fee_schedule(&Chain::Icp).min_payment;
+    if ic_cdk::api::msg_cycles_available() < min_payment as u128 {
+        return Err(format!("Attached cycles must cover the minimum payment of {} cycles", min_payment));
+    }
+    let cycles = ic_cdk::api::msg_cycles_accept(ic_cdk::api::msg_cycles_available());
+
+    let mut gas_payment = [0u8; 32];
+    gas_payment[16..].copy_from_slice(&cycles.to_be_bytes());
+    let request = create_direct_job(JobRequest {
+        chain: Chain::Icp,
+        block_hash: None,
+        block_number: None,
+        transaction_hash: None,
+        on_chain_id: None,
+        caller: Address::Principal(ic_cdk::api::msg_caller()),
+        function_hash: function_id,
+        data,
+        gas_payment: Nat256::from_be_bytes(gas_payment),
+        callback: None,
+    });
+    schedule_job(&request);
+    Ok(request.on_chain_id.expect("Job without ID").into())
+}
 
 pub fn schedule_job(job_request: &JobRequest) {
     let function = get_function(job_request.function_hash.to_vec());
     if function.is_none() {
-        update_job_status(&job_request, JobStatus::Failed("No WASM binary found for function".to_string()));
+        finish_job(&job_request, JobStatus::Failed(FailureReason::FunctionNotFound));
+        return;
+    }
+    let min_payment = crate::pricing::fee_schedule(&job_request.chain).min_payment;
+    if job_request.gas_payment.as_ref() < &Nat::from(min_payment) {
+        finish_job(&job_request, JobStatus::Failed(FailureReason::InsufficientPayment { min_payment }));
         return;
     }
     
@@ -22,33 +69,92 @@ pub fn schedule_job(job_request: &JobRequest) {
     let job_request = job_request.clone();
     let timer_id = set_timer(Duration::from_secs(0), async move {
         update_job_status(&job_request, JobStatus::Executing);
-        let wasm = function.unwrap().definition.binary;
-        let result =  match execute_job(&job_request, &wasm).await {
+        let function = function.unwrap();
+        let memory_limit = function.memory_limit.unwrap_or(DEFAULT_MEMORY_LIMIT);
+        let result =  match execute_job(&job_request, &function.definition.binary, memory_limit).await {
             Ok(_) => JobStatus::Completed,
-            Err(err) => JobStatus::Failed(err),
+            Err(reason) => JobStatus::Failed(reason),
         };
-        update_job_status(&job_request, result);
+        finish_job(&job_request, result);
     });
 }
 
-// TODO: Better error handling.
-async fn execute_job(request: &JobRequest, wasm: &[u8]) -> Result<(), String> {
+/// Recovers jobs whose execution was lost in an upgrade. Executions only live
+/// on the heap and the timers driving them don't survive upgrades, so jobs
+/// that were executing or waiting for a timer are failed and refunded, while
+/// queued jobs are scheduled again. Needs to be called on post_upgrade.
+pub fn recover_jobs() {
+    let interrupted = [
+        (JobStatusKind::Executing, "Execution was interrupted by a canister upgrade"),
+        (JobStatusKind::Waiting, "Timer was lost in a canister upgrade"),
+    ];
+    for (status, error) in interrupted {
+        for job in list_jobs_by_status(status) {
+            ic_cdk::println!("Job {:?} on chain {:?} was interrupted by an upgrade", job.request.on_chain_id, job.request.chain);
+            finish_job(&job.request, JobStatus::Failed(FailureReason::SystemError(error.to_string())));
+        }
+    }
+    for job in list_jobs_by_status(JobStatusKind::Pending) {
+        schedule_job(&job.request);
+    }
+}
+
+/// Sets the terminal status of a job, delivers the result if a callback was
+/// requested and refunds any unused gas to the caller. Refunds are only sent
+/// after the delivery, as callback transactions are paid from the gas payment.
+fn finish_job(job_request: &JobRequest, status: JobStatus) {
+    update_job_status(job_request, status);
+    match &job_request.callback {
+        Some(callback) => queue_delivery(job_request, callback.clone()),
+        None => queue_refund(job_request),
+    }
+}
+
+async fn execute_job(request: &JobRequest, wasm: &[u8], memory_limit: u64) -> Result<(), FailureReason> {
+    let pending_timers = Rc::new(Cell::new(0));
+    let job_id = request.on_chain_id.clone()
+        .ok_or_else(|| FailureReason::SystemError("Job without ID".to_string()))?;
+    let wei_per_cycle = crate::storage::get_job(&request.chain, job_id.into())
+        .ok_or_else(|| FailureReason::SystemError("Job not found".to_string()))?
+        .wei_per_cycle;
     let env = ExecutionEnvironment {
-        job_request: request.clone()
+        job_request: request.clone(),
+        wei_per_cycle,
+        fee_schedule: crate::pricing::fee_schedule(&request.chain),
+        pending_timers: pending_timers.clone(),
     };
 
-    // TODO: Enable long running tasks in main().
-    let mut execution = Execution::run_main(wasm, env)?;
+    let module = cached_module(&request.function_hash, wasm)?;
+    let mut execution = Execution::run_main(&module, memory_limit, env)?;
 
     let mut futures = FuturesUnordered::new();
     loop {
+        // Calls that reached the instruction limit of the current message
+        // need to be finished in fresh messages before running callbacks.
+        while execution.is_parked() {
+            update_job_status(request, JobStatus::Waiting);
+            sleep(Duration::from_secs(0)).await;
+            update_job_status(request, JobStatus::Executing);
+            execution.resume()?;
+        }
+
         // Move Futures from queue to FuturesUnordered
         // TODO: Turn this into a one-liner
         while let Some(async_future) = execution.next_queued_future() {
             futures.push(async_future);
         }
 
-        match futures.next().await {
+        // The job is only waiting if there are pending guest timers. Other
+        // futures (e.g. signing) are considered part of the execution.
+        let waiting = pending_timers.get() > 0;
+        if waiting {
+            update_job_status(request, JobStatus::Waiting);
+        }
+        let next = futures.next().await;
+        if waiting {
+            update_job_status(request, JobStatus::Executing);
+        }
+        match next {
             Some(result) => {
                 execution.callback(result)?;
             },
@@ -60,8 +166,23 @@ async fn execute_job(request: &JobRequest, wasm: &[u8]) -> Result<(), String> {
     Ok(())
 }
 
+/// Resolves after the given delay using an IC timer. The continuation runs
+/// in a new message, which comes with a fresh instruction counter.
+async fn sleep(delay: Duration) {
+    let (sender, receiver) = oneshot::channel();
+    set_timer(delay, async move {
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
 struct ExecutionEnvironment {
-    job_request: JobRequest
+    job_request: JobRequest,
+    // Rate recorded on the job when it was created.
+    wei_per_cycle: u64,
+    fee_schedule: FeeSchedule,
+    // Number of guest timers that have not fired yet.
+    pending_timers: Rc<Cell<u32>>,
 }
 
 impl RuntimeEnvironment for ExecutionEnvironment {
@@ -73,28 +194,42 @@ impl RuntimeEnvironment for ExecutionEnvironment {
         self.job_request.clone()
     }
 
-    fn charge_fee(&mut self, fee: u64) -> Result<(), String> {
+    fn charge_fee(&mut self, fee: u64) -> Result<(), FailureReason> {
         crate::storage::update_job(&self.job_request, |job| {
             let remaining = job.remaining_gas();
             if Nat::from(fee) > remaining {
-                return Err(format!("Insufficient gas. Tried to charge {}, but only {} remaining", fee, remaining));
+                return Err(FailureReason::OutOfGas(format!("Tried to charge {}, but only {} remaining", fee, remaining)));
             }
             job.execution_fees += fee;
             Ok(())
         })
     }
 
-    fn charge_gas(&mut self, gas: u64) -> Result<(), String> {
-        crate::storage::update_job(&self.job_request, |job| {
-            let remaining = job.remaining_gas();
-            if Nat::from(gas) > remaining {
-                return Err(format!("Insufficient gas. Tried to charge {}, but only {} remaining", gas, remaining));
-            }
-            job.gas_fees += gas;
-            Ok(())
+    fn wei_per_cycle(&self) -> u64 {
+        self.wei_per_cycle
+    }
+
+    fn host_call_surcharge(&self, name: &str) -> u64 {
+        self.fee_schedule.host_call_surcharge(name)
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn sleep(&mut self, delay: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        let pending_timers = self.pending_timers.clone();
+        pending_timers.set(pending_timers.get() + 1);
+        Box::pin(async move {
+            sleep(delay).await;
+            pending_timers.set(pending_timers.get() - 1);
         })
     }
 
+    fn set_result(&mut self, result: Vec<u8>) {
+        crate::storage::update_job(&self.job_request, |job| job.result = Some(result));
+    }
+
     fn commit(&mut self, commit: Commit) {
         crate::storage::store_commit(&self.job_request, &commit)
             .expect("Failed to store commit");
@@ -0,0 +1,205 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk_timers::set_timer_interval;
+use serde::Deserialize;
+
+use crate::chain::{Chain, EvmChain};
+use crate::execution::schedule_job;
+use crate::runtime::JobStatus;
+use crate::storage::{finalize_job, get_indexer, list_awaiting_finality, list_indexers, store_indexer};
+
+/// How often the indexer polls each chain for new blocks.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum number of blocks indexed per chain in a single poll.
+const MAX_BLOCKS_PER_RUN: u64 = 100;
+
+/// Number of confirmations to wait for if not configured otherwise.
+const DEFAULT_CONFIRMATIONS: u64 = 3;
+
+thread_local! {
+    // Chains that are currently being indexed, to avoid overlapping runs.
+    static RUNNING: RefCell<BTreeSet<EvmChain>> = RefCell::new(BTreeSet::new());
+}
+
+/// Indexing progress for a single chain.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct IndexerState {
+    /// The last block that was fully indexed. If unset, indexing starts at
+    /// the latest confirmed block.
+    pub last_indexed_block: Option<u64>,
+    /// The latest block number observed on the chain.
+    pub latest_block: Option<u64>,
+    /// Number of blocks to wait for before indexing a block.
+    pub confirmations: u64,
+    /// Whether indexing is paused for this chain.
+    pub paused: bool,
+    /// Error of the last poll, if it failed.
+    pub last_error: Option<String>,
+    /// Timestamp of the last poll (Unix nanoseconds).
+    pub last_polled_at: Option<u64>,
+}
+
+impl IndexerState {
+    fn new(confirmations: u64) -> Self {
+        Self {
+            last_indexed_block: None,
+            latest_block: None,
+            confirmations,
+            paused: false,
+            last_error: None,
+            last_polled_at: None,
+        }
+    }
+}
+
+/// Starts the timer that periodically indexes all active chains. Timers do
+/// not survive upgrades, so this needs to be called on init and post_upgrade.
+pub fn start_timer() {
+    set_timer_interval(POLL_INTERVAL, || async {
+        for (chain, state) in list_indexers() {
+            if !state.paused && crate::registry::is_enabled(&chain) {
+                index_chain(chain).await;
+            }
+        }
+    });
+}
+
+/// Starts or resumes indexing of the given chain. The chain needs a Bridge
+/// contract address in its configuration.
+pub fn resume(chain: EvmChain, confirmations: Option<u64>) -> Result<(), String> {
+    ensure_configured(&chain)?;
+    let mut state = get_indexer(&chain)
+        .unwrap_or_else(|| IndexerState::new(DEFAULT_CONFIRMATIONS));
+    state.paused = false;
+    if let Some(confirmations) = confirmations {
+        state.confirmations = confirmations;
+    }
+    store_indexer(chain, state);
+    Ok(())
+}
+
+/// Pauses indexing of the given chain.
+pub fn pause(chain: EvmChain) -> Result<(), String> {
+    let mut state = get_indexer(&chain)
+        .ok_or_else(|| format!("No indexer configured for {:?}", chain))?;
+    state.paused = true;
+    store_indexer(chain, state);
+    Ok(())
+}
+
+/// Resets the last indexed block so that all blocks after it are indexed again.
+/// Jobs that already exist are not created again.
+pub fn rewind(chain: EvmChain, last_indexed_block: u64) -> Result<(), String> {
+    let mut state = get_indexer(&chain)
+        .ok_or_else(|| format!("No indexer configured for {:?}", chain))?;
+    state.last_indexed_block = Some(last_indexed_block);
+    store_indexer(chain, state);
+    Ok(())
+}
+
+/// Indexes the next range of confirmed blocks on the given chain and schedules
+/// jobs that were finalized since the last poll. Jobs are scheduled even if
+/// indexing fails, e.g. because the providers disagree on a later block.
+async fn index_chain(chain: EvmChain) {
+    let started = RUNNING.with_borrow_mut(|running| running.insert(chain.clone()));
+    if !started {
+        return;
+    }
+    let _running = RunningGuard(chain.clone());
+    let indexed = match ensure_configured(&chain) {
+        Ok(()) => index_next_blocks(&chain).await,
+        Err(err) => Err(err),
+    };
+    let scheduled = schedule_finalized_jobs(&chain).await;
+    let errors: Vec<String> = [indexed, scheduled].into_iter().filter_map(Result::err).collect();
+    if let Some(mut state) = get_indexer(&chain) {
+        state.last_polled_at = Some(ic_cdk::api::time());
+        state.last_error = (!errors.is_empty()).then(|| errors.join("; "));
+        store_indexer(chain.clone(), state);
+    }
+}
+
+/// Checks that the chain can be indexed. Some built-in chains don't have a
+/// Bridge contract deployed, so its address needs to be set with
+/// set_chain_config first.
+fn ensure_configured(chain: &EvmChain) -> Result<(), String> {
+    match crate::registry::chain_config(chain) {
+        None => Err(format!("{:?} is not in the chain registry", chain)),
+        Some(config) if config.bridge_address.is_none() => Err(format!(
+            "Not configured: No Bridge contract address for {:?}. Set it with set_chain_config first", chain
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Marks the chain as no longer being indexed, even if indexing trapped.
+/// Locals are dropped in the cleanup callback after a trap.
+struct RunningGuard(EvmChain);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.with_borrow_mut(|running| running.remove(&self.0));
+    }
+}
+
+async fn index_next_blocks(chain: &EvmChain) -> Result<(), String> {
+    let state = get_indexer(chain).ok_or("Indexer state missing")?;
+    let latest_block = crate::consensus::latest_block_number(chain).await?;
+    let confirmed_block = latest_block.saturating_sub(state.confirmations);
+    let from_block = state.last_indexed_block.map(|block| block + 1).unwrap_or(confirmed_block);
+    let to_block = confirmed_block.min(from_block + MAX_BLOCKS_PER_RUN - 1);
+
+    if from_block <= to_block {
+        crate::evm::index_blocks(chain, from_block, to_block).await?;
+    }
+
+    // The state might have been changed by an admin in the meantime, so we
+    // only record progress if nobody rewound the indexer.
+    let mut current = get_indexer(chain).ok_or("Indexer state missing")?;
+    current.latest_block = Some(latest_block);
+    if current.last_indexed_block == state.last_indexed_block && from_block <= to_block {
+        current.last_indexed_block = Some(to_block);
+    }
+    store_indexer(chain.clone(), current);
+    Ok(())
+}
+
+/// Schedules all jobs whose block has been finalized. The block hash is checked
+/// against the canonical chain first, and jobs from orphaned blocks are marked
+/// as Reorged instead. Jobs whose block hash can't be verified are checked
+/// again on the next poll.
+async fn schedule_finalized_jobs(chain: &EvmChain) -> Result<(), String> {
+    let finalized_block = crate::consensus::finalized_block_number(chain).await?;
+    let jobs = list_awaiting_finality(&Chain::Evm(chain.clone()), finalized_block);
+    // Jobs are often in the same block, so each hash is only fetched once.
+    let mut canonical_hashes = BTreeMap::new();
+    let mut result = Ok(());
+    for job in jobs {
+        let Some(block_number) = job.request.block_number else {
+            result = Err(format!("Job {:?} without block number", job.request.on_chain_id));
+            continue;
+        };
+        let canonical_hash = match canonical_hashes.get(&block_number) {
+            Some(hash) => *hash,
+            None => match crate::consensus::block_hash(chain, block_number).await {
+                Ok(hash) => *canonical_hashes.entry(block_number).or_insert(hash),
+                Err(err) => {
+                    result = Err(err);
+                    continue;
+                }
+            },
+        };
+        if job.request.block_hash == Some(canonical_hash.0.into()) {
+            finalize_job(&job.request, JobStatus::Pending);
+            schedule_job(&job.request);
+        } else {
+            ic_cdk::println!("Job {:?} on chain {:?} was in orphaned block {:?}", job.request.on_chain_id, chain, job.request.block_hash);
+            finalize_job(&job.request, JobStatus::Reorged);
+        }
+    }
+    result
+}
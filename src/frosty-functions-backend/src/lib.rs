@@ -1,25 +1,50 @@
+mod access;
 mod chain;
+mod consensus;
+mod delivery;
 mod evm;
 mod execution;
+mod indexer;
+mod migration;
+mod outbox;
+mod pricing;
+mod refund;
+mod registry;
 mod repository;
 mod runtime;
 mod signer;
 mod simulation;
 mod storage;
 
-use std::cell::RefCell;
-
-use candid::CandidType;
+use candid::{CandidType, Nat, Principal};
 use chain::{Chain};
 use evm_rpc_types::Nat256;
 use serde::{Deserialize, Serialize};
 
-use crate::{chain::Caller, execution::schedule_job, repository::{DeployResult, FunctionDefinition, FunctionId, FunctionState}, runtime::{Commit, Job, JobRequest}, signer::{derivation_path_for_caller, derivation_path_for_function}, simulation::SimulationResult};
+use crate::{chain::{Caller, EvmChain}, repository::{DeployResult, FunctionDefinition, FunctionId, FunctionState}, runtime::{Commit, DEFAULT_MEMORY_LIMIT, Job, JobFilter, JobPage, JobRequest, ModuleCacheMetrics}, signer::{derivation_path_for_caller, derivation_path_for_function}, simulation::{CostEstimate, SimulationResult}};
+use crate::access::{InitArgs, Role, RoleChange, RoleGrant, ensure_role};
+use crate::consensus::ConsensusAudit;
+use crate::indexer::IndexerState;
+use crate::pricing::{FeeSchedule, PricingConfig, PricingState};
+use crate::registry::ChainConfig;
 use crate::signer::{Signer, ThresholdSigner};
 
-// TODO: Remove again
-thread_local! {
-    static VALID_API_KEYS: RefCell<Option<Vec<String>>> = RefCell::new(None);
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    crate::migration::init();
+    crate::access::init(args);
+    crate::indexer::start_timer();
+    crate::pricing::start_timer();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    crate::migration::run_migrations();
+    crate::access::init(args);
+    crate::indexer::start_timer();
+    crate::pricing::start_timer();
+    crate::outbox::start();
+    crate::execution::recover_jobs();
 }
 
 #[ic_cdk::query]
@@ -44,52 +69,198 @@ fn get_job(chain: Chain, job_id: Nat256) -> Option<Job> {
     crate::storage::get_job(&chain, job_id.into())
 }
 
-/// Deploy a new function.
+/// Returns up to `limit` jobs matching the filter in creation order. Pass the
+/// `next_cursor` of a page to get the next page.
+#[ic_cdk::query]
+fn list_jobs(filter: JobFilter, cursor: Option<u64>, limit: u64) -> JobPage {
+    crate::storage::list_jobs(&filter, cursor.unwrap_or_default(), limit.min(100) as usize)
+}
+
+/// Deploy a new function. Requires the Deployer role. Executions are limited to
+/// `memory_limit` bytes of linear memory, 64 MiB if not set.
 #[ic_cdk::update]
-fn deploy_function(definition: FunctionDefinition, api_key: Option<String>) -> DeployResult {
-    let valid_keys = VALID_API_KEYS.with_borrow(|keys| keys.clone().unwrap_or_default());
-    if !valid_keys.is_empty() {
-        if api_key.is_none() {
-            return DeployResult::Error("Deployment is currently restricted to private alpha users. Reach out to frosty@web3.services for an API key".to_string());
-        } else if !valid_keys.contains(&api_key.unwrap()) {
-            return DeployResult::Error("Invalid API key provided.".to_string());
-        }
+fn deploy_function(definition: FunctionDefinition, memory_limit: Option<u64>) -> DeployResult {
+    if ensure_role(Role::Deployer).is_err() {
+        return DeployResult::Error("Deployment is currently restricted to private alpha users. Reach out to frosty@web3.services for access".to_string());
     }
-    crate::repository::deploy_function(definition)
+    crate::repository::deploy_function(definition, memory_limit)
 }
 
+/// Queues another attempt for a refund that failed after all retries. Requires
+/// the Admin role.
 #[ic_cdk::update]
-fn tmp_set_api_keys(admin_key: String, api_keys: Option<Vec<String>>) -> Result<(), String> {
-    let keys = VALID_API_KEYS.with_borrow(|keys| keys.clone());
-    if keys.is_some() && keys.unwrap().get(0) != Some(&admin_key) {
-        return Err("Invalid admin key".to_string());
-    }
-    VALID_API_KEYS.replace(api_keys);
-    Ok(())
+fn retry_refund(chain: Chain, job_id: Nat256) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    crate::refund::retry_refund(&chain, job_id.into())
+}
+
+/// Invokes a function directly from a canister, without going through a bridge
+/// contract. Attached cycles are used as gas payment and unused cycles are
+/// refunded. Returns the ID of the job on the `Icp` chain.
+#[ic_cdk::update]
+fn invoke_function(function_id: FunctionId, data: Vec<u8>) -> Result<Nat, String> {
+    crate::execution::invoke_function(function_id, data)
+}
+
+/// Grants a role to a principal. Owner and Admin can only be granted by owners,
+/// the other roles also by admins.
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+    crate::access::grant_role(principal, role)
+}
+
+/// Revokes a role from a principal. Requires the same role as granting it.
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), String> {
+    crate::access::revoke_role(principal, role)
+}
+
+/// Returns all granted roles. Controllers are owners without an explicit grant.
+#[ic_cdk::query]
+fn list_roles() -> Vec<RoleGrant> {
+    crate::storage::list_role_grants()
 }
 
-/// Looks for jobs in the specified block on the given chain.
-/// TODO: Currently this call is exposed to the public and invoked from the frontend.
-/// This is problematic as the call incurs costs the RPC and could be used to drain cycles.
-/// In the future we could require addition of cycles to the call that are refunded only
-/// if new jobs were found in the block. We should also provide an (off chain?) indexer to
-/// watch for new blocks and call this method automatically.  
+/// Returns entries of the audit log of role changes.
+#[ic_cdk::query]
+fn get_role_changes(offset: u64, limit: u64) -> Vec<RoleChange> {
+    crate::storage::list_role_changes(offset, limit)
+}
+
+/// Looks for jobs in the specified block on the given chain. Blocks are indexed
+/// automatically by the indexer, so this is only meant for manual recovery.
+/// Jobs are scheduled by the indexer once their block is finalized.
 #[ic_cdk::update]
 async fn index_block(chain: Chain, block_number: u64) -> Result<Vec<JobRequest>, String> {
+    ensure_role(Role::IndexerOperator)?;
     match &chain {
-        Chain::Evm(evm_chain) => {
-            let jobs = crate::evm::index_block(evm_chain, block_number).await?;
-            for job in &jobs {
-                schedule_job(job);
-            }
-            Ok(jobs)
-        }
+        Chain::Evm(evm_chain) => crate::evm::index_block(evm_chain, block_number).await,
+        Chain::Icp => Err("Jobs on ICP are created by invoke_function and not indexed".to_string()),
     }
 }
 
+/// Returns the indexing progress of all configured chains.
+#[ic_cdk::query]
+fn get_indexer_status() -> Vec<(EvmChain, IndexerState)> {
+    crate::storage::list_indexers()
+}
+
+/// Starts or resumes automated indexing of the given chain, optionally
+/// changing the number of confirmations to wait for.
+#[ic_cdk::update]
+fn resume_indexer(chain: EvmChain, confirmations: Option<u64>) -> Result<(), String> {
+    ensure_role(Role::IndexerOperator)?;
+    crate::indexer::resume(chain, confirmations)
+}
+
+#[ic_cdk::update]
+fn pause_indexer(chain: EvmChain) -> Result<(), String> {
+    ensure_role(Role::IndexerOperator)?;
+    crate::indexer::pause(chain)
+}
+
+/// Resets the indexer so that all blocks after last_indexed_block are indexed again.
+#[ic_cdk::update]
+fn rewind_indexer(chain: EvmChain, last_indexed_block: u64) -> Result<(), String> {
+    ensure_role(Role::IndexerOperator)?;
+    crate::indexer::rewind(chain, last_indexed_block)
+}
+
+/// Returns the configuration of all built-in and registered EVM chains.
+#[ic_cdk::query]
+fn get_chains() -> Vec<(EvmChain, ChainConfig)> {
+    crate::registry::list_chains()
+}
+
+/// Adds or updates an EVM chain in the chain registry. Custom chains can be
+/// added this way without upgrading the canister.
+#[ic_cdk::update]
+fn set_chain_config(chain: EvmChain, config: ChainConfig) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    crate::registry::set_chain_config(chain, config)
+}
+
+/// Removes an EVM chain from the chain registry. Built-in chains fall back to
+/// their default configuration.
+#[ic_cdk::update]
+fn remove_chain_config(chain: EvmChain) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    crate::registry::remove_chain_config(&chain)
+}
+
+/// Returns entries of the audit log of disagreements between RPC providers. Only
+/// the most recent 10,000 entries are kept.
+#[ic_cdk::query]
+fn get_consensus_audits(offset: u64, limit: u64) -> Vec<ConsensusAudit> {
+    crate::storage::list_consensus_audits(offset, limit.min(100))
+}
+
+/// Returns the number of identical RPC responses required to create jobs on the given chain.
+#[ic_cdk::query]
+fn get_consensus_threshold(chain: EvmChain) -> u32 {
+    crate::consensus::threshold(&chain)
+}
+
+#[ic_cdk::update]
+fn set_consensus_threshold(chain: EvmChain, threshold: u32) -> Result<(), String> {
+    ensure_role(Role::IndexerOperator)?;
+    crate::consensus::set_threshold(chain, threshold)
+}
+
+/// Returns metrics of the cache for compiled WASM modules.
+#[ic_cdk::query]
+fn get_module_cache_metrics() -> ModuleCacheMetrics {
+    crate::runtime::module_cache_metrics()
+}
+
+/// Returns the fees charged for jobs on the given chain.
+#[ic_cdk::query]
+fn get_fee_schedule(chain: Chain) -> FeeSchedule {
+    crate::pricing::fee_schedule(&chain)
+}
+
+#[ic_cdk::update]
+fn set_fee_schedule(chain: Chain, schedule: FeeSchedule) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    crate::pricing::set_fee_schedule(chain, schedule)
+}
+
+/// Returns the pricing configuration and current exchange rate of the given chain.
+#[ic_cdk::query]
+fn get_pricing(chain: Chain) -> Option<PricingState> {
+    crate::storage::get_pricing(&chain)
+}
+
+/// Returns the conversion rate between cycles and wei that new jobs on the given chain use.
+#[ic_cdk::query]
+fn get_wei_per_cycle(chain: Chain) -> u64 {
+    crate::pricing::wei_per_cycle(&chain)
+}
+
+#[ic_cdk::update]
+fn set_pricing_config(chain: Chain, config: PricingConfig) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    crate::pricing::configure(chain, config)
+}
+
+/// Estimates the cost of invoking a function by simulating the invocation. If no
+/// WASM binary is provided, the deployed function with the requested hash is used.
+#[ic_cdk::query]
+fn estimate_cost(request: JobRequest, wasm: Option<Vec<u8>>) -> Result<CostEstimate, String> {
+    let (wasm, memory_limit) = match wasm {
+        Some(wasm) => (wasm, DEFAULT_MEMORY_LIMIT),
+        None => {
+            let function = crate::storage::get_function(request.function_hash.clone())
+                .ok_or("Function not found")?;
+            (function.definition.binary, function.memory_limit.unwrap_or(DEFAULT_MEMORY_LIMIT))
+        }
+    };
+    crate::simulation::estimate_cost(request, &wasm, memory_limit)
+}
+
 #[ic_cdk::query]
 fn simulate_execution(request: JobRequest, wasm: Vec<u8>) -> Result<SimulationResult, String> {
-    crate::simulation::simulate_job(request, &wasm)
+    crate::simulation::simulate_job(request, &wasm, DEFAULT_MEMORY_LIMIT)
 }
 
 #[ic_cdk::query]
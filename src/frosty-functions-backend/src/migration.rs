@@ -0,0 +1,430 @@
+use candid::{CandidType, Decode, Encode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::access::RoleChange;
+use crate::consensus::ConsensusAudit;
+use crate::indexer::IndexerState;
+use crate::outbox::OutboxEntry;
+use crate::pricing::{DEFAULT_WEI_PER_CYCLE, FeeSchedule, PricingState};
+use crate::refund::Refund;
+use crate::registry::ChainConfig;
+use crate::repository::FunctionState;
+use crate::runtime::{Commit, FailureReason, Job, JobRequest, JobStatus};
+use crate::storage::{get_schema_version, index_jobs, rewrite_values, set_schema_version};
+
+/// Current version of the storage layout. Canisters installed before the
+/// storage header was introduced are at version 0.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// First byte of values stored in a versioned envelope. Values stored before
+/// envelopes were introduced are raw Candid, which always starts with "DIDL".
+const ENVELOPE_MAGIC: u8 = 0xF5;
+
+/// A type stored as a value in stable memory. Values are wrapped in an envelope
+/// with the layout version of the type, so that values stored by older versions
+/// of the canister can still be decoded after the type changed.
+pub trait Versioned: CandidType + DeserializeOwned {
+    /// Layout version of new values. Needs to be bumped whenever the type changes
+    /// in a way that Candid can't decode old values into the new type, e.g. when
+    /// adding a field that is not an Option. The old layout then needs to be
+    /// handled in `decode_legacy`.
+    const VERSION: u8 = 1;
+
+    /// Decodes a value stored with an older layout version. Version 0 are raw
+    /// Candid values from before envelopes were introduced. The default is
+    /// sufficient for types that only had optional fields added.
+    fn decode_legacy(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        Decode!(bytes, Self).map_err(|err| err.to_string())
+    }
+}
+
+/// Encodes a value in an envelope with the current layout version of its type.
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![ENVELOPE_MAGIC, T::VERSION];
+    bytes.extend(Encode!(value).unwrap());
+    bytes
+}
+
+/// Decodes a value in any layout version up to the current one.
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes {
+        [ENVELOPE_MAGIC, version, candid @ ..] if *version == T::VERSION => {
+            Decode!(candid, T).map_err(|err| err.to_string())
+        }
+        [ENVELOPE_MAGIC, version, candid @ ..] if *version < T::VERSION => T::decode_legacy(*version, candid),
+        [ENVELOPE_MAGIC, version, ..] => {
+            Err(format!("Layout version {} is newer than the supported version {}", version, T::VERSION))
+        }
+        candid => T::decode_legacy(0, candid),
+    }
+}
+
+impl Versioned for ChainConfig {}
+impl Versioned for Commit {}
+impl Versioned for ConsensusAudit {}
+impl Versioned for FeeSchedule {}
+impl Versioned for FunctionState {}
+impl Versioned for IndexerState {}
+impl Versioned for OutboxEntry {}
+impl Versioned for PricingState {}
+impl Versioned for RoleChange {}
+
+impl Versioned for Job {
+    fn decode_legacy(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        if version != 0 {
+            return Err(format!("Unknown layout version {} of Job", version));
+        }
+        // Depending on the version of the canister that stored it, a job might
+        // not have wei_per_cycle yet or use the old Failed(text) status.
+        if let Ok(job) = Decode!(bytes, Job) {
+            return Ok(job);
+        }
+        Decode!(bytes, LegacyJob)
+            .map(Job::from)
+            .map_err(|err| format!("Failed to decode legacy job: {}", err))
+    }
+}
+
+/// Layout of jobs before failure reasons were introduced.
+#[derive(CandidType, Deserialize)]
+struct LegacyJob {
+    request: JobRequest,
+    status: LegacyJobStatus,
+    created_at: u64,
+    commit_ids: Vec<u64>,
+    base_fee: u64,
+    execution_fees: u64,
+    gas_fees: u64,
+    // Missing in jobs from before per-chain pricing.
+    wei_per_cycle: Option<u64>,
+    refund: Option<Refund>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum LegacyJobStatus {
+    AwaitingFinality,
+    Reorged,
+    Pending,
+    Executing,
+    Waiting,
+    Completed,
+    Failed(String),
+}
+
+impl From<LegacyJob> for Job {
+    fn from(job: LegacyJob) -> Self {
+        let status = match job.status {
+            LegacyJobStatus::AwaitingFinality => JobStatus::AwaitingFinality,
+            LegacyJobStatus::Reorged => JobStatus::Reorged,
+            LegacyJobStatus::Pending => JobStatus::Pending,
+            LegacyJobStatus::Executing => JobStatus::Executing,
+            LegacyJobStatus::Waiting => JobStatus::Waiting,
+            LegacyJobStatus::Completed => JobStatus::Completed,
+            LegacyJobStatus::Failed(message) => JobStatus::Failed(legacy_failure(message)),
+        };
+        Job {
+            request: job.request,
+            status,
+            created_at: job.created_at,
+            commit_ids: job.commit_ids,
+            base_fee: job.base_fee,
+            execution_fees: job.execution_fees,
+            gas_fees: job.gas_fees,
+            wei_per_cycle: job.wei_per_cycle.unwrap_or(DEFAULT_WEI_PER_CYCLE),
+            refund: job.refund,
+            result: None,
+            delivery: None,
+        }
+    }
+}
+
+/// Errors were not classified before, but most of them were traps in user code.
+fn legacy_failure(message: String) -> FailureReason {
+    match message.as_str() {
+        "No WASM binary found for function" => FailureReason::FunctionNotFound,
+        _ => FailureReason::UncaughtException(message),
+    }
+}
+
+struct Migration {
+    /// Schema version after the migration.
+    version: u32,
+    description: &'static str,
+    run: fn(),
+}
+
+/// Migrations in order of their version. They run in post_upgrade, so they
+/// need to fit into the instruction limit of upgrades.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Store values in versioned envelopes",
+        run: rewrite_values,
+    },
+    Migration {
+        version: 2,
+        description: "Index jobs by function, caller and status",
+        run: index_jobs,
+    },
+];
+
+/// Marks the storage of a freshly installed canister as up to date.
+pub fn init() {
+    set_schema_version(SCHEMA_VERSION);
+}
+
+/// Brings the storage to the current schema version. Traps if it was written by
+/// a newer version of the canister, as downgrades are not supported.
+pub fn run_migrations() {
+    let version = get_schema_version();
+    if version > SCHEMA_VERSION {
+        ic_cdk::trap(format!("Storage schema version {} is newer than {}", version, SCHEMA_VERSION));
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > version) {
+        ic_cdk::println!("Migrating storage to version {}: {}", migration.version, migration.description);
+        (migration.run)();
+        set_schema_version(migration.version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evm_rpc_types::{Hex20, Hex32, Nat256};
+
+    use super::*;
+    use crate::chain::{Address, Chain, EvmChain};
+
+    /// Layouts stored by the initial release of the canister.
+    mod v0 {
+        use candid::CandidType;
+        use evm_rpc_types::{Hex32, Nat256};
+
+        use crate::chain::{Address, Chain};
+
+        #[derive(CandidType)]
+        pub struct JobRequest {
+            pub chain: Chain,
+            pub block_hash: Option<Hex32>,
+            pub block_number: Option<u64>,
+            pub transaction_hash: Option<Hex32>,
+            pub on_chain_id: Option<Nat256>,
+            pub caller: Address,
+            pub function_hash: Vec<u8>,
+            pub data: Vec<u8>,
+            pub gas_payment: Nat256,
+        }
+
+        #[derive(CandidType)]
+        pub struct Job {
+            pub request: JobRequest,
+            pub status: JobStatus,
+            pub created_at: u64,
+            pub commit_ids: Vec<u64>,
+            pub base_fee: u64,
+            pub execution_fees: u64,
+            pub gas_fees: u64,
+        }
+
+        #[derive(CandidType)]
+        #[allow(dead_code)]
+        pub enum JobStatus {
+            Pending,
+            Executing,
+            Waiting,
+            Completed,
+            Failed(String),
+        }
+
+        #[derive(CandidType)]
+        pub struct FunctionDefinition {
+            pub source: String,
+            pub compiler: String,
+            pub binary: Vec<u8>,
+        }
+
+        #[derive(CandidType)]
+        pub struct FunctionState {
+            pub definition: FunctionDefinition,
+            pub hash: Vec<u8>,
+            pub deployed_at: u64,
+            pub is_verified: bool,
+        }
+    }
+
+    /// Layout of jobs with per-chain pricing, but before failure reasons.
+    #[derive(CandidType)]
+    struct JobWithPricing {
+        request: v0::JobRequest,
+        status: v0::JobStatus,
+        created_at: u64,
+        commit_ids: Vec<u64>,
+        base_fee: u64,
+        execution_fees: u64,
+        gas_fees: u64,
+        wei_per_cycle: u64,
+    }
+
+    fn job_id(id: u8) -> Nat256 {
+        let mut bytes = [0u8; 32];
+        bytes[31] = id;
+        Nat256::from_be_bytes(bytes)
+    }
+
+    fn v0_request() -> v0::JobRequest {
+        v0::JobRequest {
+            chain: Chain::Evm(EvmChain::ArbitrumSepolia),
+            block_hash: Some(Hex32::from([1u8; 32])),
+            block_number: Some(100),
+            transaction_hash: Some(Hex32::from([2u8; 32])),
+            on_chain_id: Some(job_id(7)),
+            caller: Address::EvmAddress(Hex20::from([3u8; 20])),
+            function_hash: vec![4u8; 32],
+            data: vec![5, 6],
+            gas_payment: job_id(200),
+        }
+    }
+
+    fn v0_job(status: v0::JobStatus) -> v0::Job {
+        v0::Job {
+            request: v0_request(),
+            status,
+            created_at: 1_700_000_000_000_000_000,
+            commit_ids: vec![0, 1],
+            base_fee: 10,
+            execution_fees: 20,
+            gas_fees: 30,
+        }
+    }
+
+    fn current_job() -> Job {
+        Job {
+            request: JobRequest {
+                chain: Chain::Icp,
+                block_hash: None,
+                block_number: None,
+                transaction_hash: None,
+                on_chain_id: Some(job_id(1)),
+                caller: Address::EvmAddress(Hex20::from([3u8; 20])),
+                function_hash: vec![4u8; 32],
+                data: vec![],
+                gas_payment: job_id(200),
+                callback: Some(Hex20::from([9u8; 20])),
+            },
+            status: JobStatus::Failed(FailureReason::OutOfMemory { limit: 1024 }),
+            created_at: 42,
+            commit_ids: vec![3],
+            base_fee: 10,
+            execution_fees: 20,
+            gas_fees: 30,
+            wei_per_cycle: 5,
+            refund: None,
+            result: Some(vec![1, 2, 3]),
+            delivery: None,
+        }
+    }
+
+    #[test]
+    fn decodes_initial_job_layout() {
+        let bytes = Encode!(&v0_job(v0::JobStatus::Completed)).unwrap();
+        let job: Job = decode(&bytes).unwrap();
+        assert!(matches!(job.status, JobStatus::Completed));
+        assert_eq!(job.request.on_chain_id, Some(job_id(7)));
+        assert_eq!(job.request.block_number, Some(100));
+        assert!(job.request.callback.is_none());
+        assert_eq!(job.commit_ids, vec![0, 1]);
+        assert_eq!(job.total_cost(), 60);
+        assert_eq!(job.wei_per_cycle, DEFAULT_WEI_PER_CYCLE);
+        assert!(job.refund.is_none());
+        assert!(job.result.is_none());
+        assert!(job.delivery.is_none());
+    }
+
+    #[test]
+    fn decodes_legacy_failures() {
+        let bytes = Encode!(&v0_job(v0::JobStatus::Failed("No WASM binary found for function".to_string()))).unwrap();
+        let job: Job = decode(&bytes).unwrap();
+        assert!(matches!(job.status, JobStatus::Failed(FailureReason::FunctionNotFound)));
+
+        let bytes = Encode!(&v0_job(v0::JobStatus::Failed("unreachable".to_string()))).unwrap();
+        let job: Job = decode(&bytes).unwrap();
+        assert!(matches!(job.status, JobStatus::Failed(FailureReason::UncaughtException(message)) if message == "unreachable"));
+    }
+
+    #[test]
+    fn decodes_job_layout_with_pricing() {
+        let v0::Job { request, created_at, commit_ids, base_fee, execution_fees, gas_fees, .. } = v0_job(v0::JobStatus::Pending);
+        let fixture = JobWithPricing {
+            request,
+            status: v0::JobStatus::Failed("Out of gas".to_string()),
+            created_at,
+            commit_ids,
+            base_fee,
+            execution_fees,
+            gas_fees,
+            wei_per_cycle: 2_500,
+        };
+        let job: Job = decode(&Encode!(&fixture).unwrap()).unwrap();
+        assert_eq!(job.wei_per_cycle, 2_500);
+        assert!(matches!(job.status, JobStatus::Failed(FailureReason::UncaughtException(_))));
+    }
+
+    #[test]
+    fn decodes_unversioned_current_job() {
+        let job: Job = decode(&Encode!(&current_job()).unwrap()).unwrap();
+        assert!(matches!(job.status, JobStatus::Failed(FailureReason::OutOfMemory { limit: 1024 })));
+        assert_eq!(job.wei_per_cycle, 5);
+        assert_eq!(job.result, Some(vec![1, 2, 3]));
+        assert_eq!(job.request.callback, Some(Hex20::from([9u8; 20])));
+    }
+
+    #[test]
+    fn round_trips_envelope() {
+        let bytes = encode(&current_job());
+        assert_eq!(bytes[..2], [ENVELOPE_MAGIC, <Job as Versioned>::VERSION]);
+        let job: Job = decode(&bytes).unwrap();
+        assert!(matches!(job.request.chain, Chain::Icp));
+        assert_eq!(job.created_at, 42);
+        assert_eq!(job.result, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_newer_layout() {
+        let mut bytes = encode(&current_job());
+        bytes[1] = <Job as Versioned>::VERSION + 1;
+        assert!(decode::<Job>(&bytes).is_err());
+    }
+
+    #[test]
+    fn decodes_initial_function_layout() {
+        let fixture = v0::FunctionState {
+            definition: v0::FunctionDefinition {
+                source: "export function main(): void {}".to_string(),
+                compiler: "asc".to_string(),
+                binary: vec![0, 97, 115, 109],
+            },
+            hash: vec![4u8; 32],
+            deployed_at: 1_700_000_000_000_000_000,
+            is_verified: false,
+        };
+        let function: FunctionState = decode(&Encode!(&fixture).unwrap()).unwrap();
+        assert_eq!(function.definition.binary, vec![0, 97, 115, 109]);
+        assert_eq!(function.hash, vec![4u8; 32]);
+        assert!(function.validation.is_none());
+        assert!(function.memory_limit.is_none());
+    }
+
+    #[test]
+    fn decodes_initial_commit_layout() {
+        let commit = Commit {
+            timestamp: 1,
+            title: "main".to_string(),
+            logs: vec![],
+            instructions: 1_000,
+            fees: 20,
+        };
+        let decoded: Commit = decode(&Encode!(&commit).unwrap()).unwrap();
+        assert_eq!(decoded.title, "main");
+        assert_eq!(decoded.fees, 20);
+    }
+}
@@ -0,0 +1,112 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk_timers::set_timer;
+use serde::Deserialize;
+
+use crate::runtime::JobRequest;
+use crate::storage::{list_outbox_entries, push_outbox_entry, remove_outbox_entry};
+
+thread_local! {
+    // Whether the outbox is being processed. Transactions are sent one at a
+    // time from the canister's main EVM account as they would otherwise race
+    // for the same nonce.
+    static PROCESSING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Transaction sent from the canister's main EVM account on behalf of a job.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Outgoing {
+    /// Delivers the result of the job to its callback contract.
+    Delivery(JobRequest),
+    /// Refunds the unused gas of the job to its caller.
+    Refund(JobRequest),
+}
+
+/// Transaction waiting in the outbox. The outbox is kept in stable memory, so
+/// that queued transactions survive upgrades.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutboxEntry {
+    pub transaction: Outgoing,
+    /// Earliest time to process the transaction (Unix nanoseconds).
+    pub not_before: u64,
+}
+
+/// Queues a transaction and starts processing the outbox if necessary.
+pub fn enqueue(transaction: Outgoing) {
+    enqueue_after(transaction, Duration::ZERO);
+}
+
+/// Queues a transaction that should be processed after the given delay, e.g.
+/// to retry it with backoff.
+pub fn enqueue_after(transaction: Outgoing, delay: Duration) {
+    push_outbox_entry(OutboxEntry {
+        transaction,
+        not_before: ic_cdk::api::time() + delay.as_nanos() as u64,
+    });
+    schedule(delay);
+}
+
+/// Resumes processing of the transactions queued before an upgrade.
+pub fn start() {
+    schedule(Duration::ZERO);
+}
+
+fn schedule(delay: Duration) {
+    set_timer(delay, process_queue());
+}
+
+async fn process_queue() {
+    if PROCESSING.replace(true) {
+        return;
+    }
+    let _processing = ProcessingGuard;
+    loop {
+        let now = ic_cdk::api::time();
+        let entries = list_outbox_entries();
+        let Some((id, entry)) = entries.iter().find(|(_, entry)| entry.not_before <= now).cloned() else {
+            if let Some(next) = entries.iter().map(|(_, entry)| entry.not_before).min() {
+                schedule(Duration::from_nanos(next - now));
+            }
+            return;
+        };
+        let mut entry_guard = EntryGuard { id, transaction: entry.transaction.clone(), processed: false };
+        match entry.transaction {
+            Outgoing::Delivery(request) => crate::delivery::send_callback(&request).await,
+            Outgoing::Refund(request) => crate::refund::send_refund(&request).await,
+        }
+        entry_guard.processed = true;
+    }
+}
+
+/// Marks the outbox as idle again, even if processing trapped. Locals are
+/// dropped in the cleanup callback after a trap.
+struct ProcessingGuard;
+
+impl Drop for ProcessingGuard {
+    fn drop(&mut self) {
+        PROCESSING.set(false);
+    }
+}
+
+/// Removes an entry from the outbox once it was processed. Entries whose
+/// processing trapped are removed as well, so that they can't block the outbox.
+/// Refunds are marked as failed in that case, so that they can be retried.
+struct EntryGuard {
+    id: u64,
+    transaction: Outgoing,
+    processed: bool,
+}
+
+impl Drop for EntryGuard {
+    fn drop(&mut self) {
+        if !self.processed {
+            ic_cdk::println!("ERROR: Processing outbox entry {} trapped, dropping it", self.id);
+            if let Outgoing::Refund(request) = &self.transaction {
+                crate::refund::fail_refund(request, "Processing the refund trapped".to_string());
+            }
+        }
+        remove_outbox_entry(self.id);
+    }
+}
@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use candid::{CandidType, Principal, Reserved};
+use ic_cdk::call::Call;
+use ic_cdk_timers::set_timer_interval;
+use serde::Deserialize;
+
+use crate::chain::{Chain, EvmChain};
+use crate::storage::{get_fee_schedule, get_pricing, list_pricing, store_fee_schedule, store_pricing};
+
+/// Conversion rate between cycles and native currency (wei) used if no rate has
+/// been configured or fetched for a chain yet.
+/// Note: Add the time of writing, 1 cycle costs approximatey 430 wei,
+/// but we need to leave some margin for price fluctuations.
+pub const DEFAULT_WEI_PER_CYCLE: u64 = 1000;
+
+/// Base fee per execution in wei, used for chains without a fee schedule.
+const DEFAULT_BASE_FEE_WEI: u64 = 1_000_000_000_000;  // 1,000 gwei
+
+/// Minimum payment on Ethereum mainnet, where refund and callback transactions
+/// cost considerably more than on L2s.
+const ETHEREUM_MAINNET_MIN_PAYMENT_WEI: u64 = 1_000_000_000_000_000;  // 0.001 ETH
+
+/// Base fee per execution in cycles, used for ICP without a fee schedule.
+const DEFAULT_BASE_FEE_CYCLES: u64 = 1_000_000_000;
+
+/// How often exchange rates are refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Cycles attached to calls to the exchange rate canister.
+/// See https://internetcomputer.org/docs/references/system-canisters/xrc
+const XRC_CALL_CYCLES: u128 = 1_000_000_000;
+
+/// Number of cycles per XDR, which is fixed by the IC.
+const CYCLES_PER_XDR: u128 = 1_000_000_000_000;
+
+const WEI_PER_ETH: u128 = 1_000_000_000_000_000_000;
+
+/// Pricing configuration and current exchange rate of a chain.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PricingState {
+    pub config: PricingConfig,
+    /// Latest rate derived from the price source, including margin and bounds.
+    pub wei_per_cycle: Option<u64>,
+    /// Timestamp of the last successful refresh (Unix nanoseconds).
+    pub updated_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PricingConfig {
+    pub source: PriceSource,
+    /// Margin added on top of the market rate to account for price fluctuations.
+    pub margin_percent: u64,
+    /// Lower bound for the rate, protecting against faulty price feeds.
+    pub min_wei_per_cycle: u64,
+    /// Upper bound for the rate, protecting against faulty price feeds.
+    pub max_wei_per_cycle: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PriceSource {
+    /// A fixed rate, e.g. for testnets.
+    Fixed { wei_per_cycle: u64 },
+    /// Any canister implementing the `get_exchange_rate` method of the exchange
+    /// rate canister (XRC), so a local stub can stand in for the XRC. The price
+    /// of `symbol` (e.g. "ETH") is fetched in XDR.
+    ExchangeRateCanister { canister_id: Principal, symbol: String },
+}
+
+/// Fees charged on a chain, denominated in the smallest unit of its native currency.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FeeSchedule {
+    /// Symbol of the native currency, e.g. "ETH".
+    pub currency: String,
+    /// Number of decimals of the native currency, e.g. 18 for ETH.
+    pub decimals: u8,
+    /// Fee charged for every execution.
+    pub base_fee: u64,
+    /// Jobs with a lower gas payment are not executed.
+    pub min_payment: u64,
+    /// Flat fees charged per call of the given host functions, on top of the
+    /// cycles consumed.
+    pub host_call_surcharges: Vec<(String, u64)>,
+}
+
+impl FeeSchedule {
+    /// Default fee schedule of the given chain. Gas on ICP is paid in cycles.
+    pub fn default_for(chain: &Chain) -> Self {
+        match chain {
+            Chain::Evm(EvmChain::EthereumMainnet) => Self {
+                min_payment: ETHEREUM_MAINNET_MIN_PAYMENT_WEI,
+                ..Self::default()
+            },
+            Chain::Evm(_) => Self::default(),
+            Chain::Icp => Self {
+                currency: "cycles".to_string(),
+                decimals: 12,
+                base_fee: DEFAULT_BASE_FEE_CYCLES,
+                min_payment: DEFAULT_BASE_FEE_CYCLES,
+                host_call_surcharges: Vec::new(),
+            },
+        }
+    }
+
+    pub fn host_call_surcharge(&self, name: &str) -> u64 {
+        self.host_call_surcharges.iter()
+            .find(|(function, _)| function == name)
+            .map(|(_, surcharge)| *surcharge)
+            .unwrap_or(0)
+    }
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        Self {
+            currency: "ETH".to_string(),
+            decimals: 18,
+            base_fee: DEFAULT_BASE_FEE_WEI,
+            min_payment: DEFAULT_BASE_FEE_WEI,
+            host_call_surcharges: Vec::new(),
+        }
+    }
+}
+
+/// Returns the fee schedule of the given chain.
+pub fn fee_schedule(chain: &Chain) -> FeeSchedule {
+    get_fee_schedule(chain).unwrap_or_else(|| FeeSchedule::default_for(chain))
+}
+
+/// Sets the fee schedule of the given chain. Only applies to jobs created afterwards.
+pub fn set_fee_schedule(chain: Chain, schedule: FeeSchedule) -> Result<(), String> {
+    if schedule.min_payment < schedule.base_fee {
+        return Err("min_payment must cover at least the base_fee".to_string());
+    }
+    store_fee_schedule(chain, schedule);
+    Ok(())
+}
+
+/// Returns the rate that should be used for new jobs on the given chain. Gas on
+/// ICP is paid in cycles, so no conversion is needed.
+pub fn wei_per_cycle(chain: &Chain) -> u64 {
+    if let Chain::Icp = chain {
+        return 1;
+    }
+    get_pricing(chain)
+        .and_then(|state| state.wei_per_cycle)
+        .unwrap_or(DEFAULT_WEI_PER_CYCLE)
+}
+
+/// Sets the pricing configuration for the given chain. The rate is refreshed
+/// with the next timer run.
+pub fn configure(chain: Chain, config: PricingConfig) -> Result<(), String> {
+    if let Chain::Icp = chain {
+        return Err("Gas on ICP is paid in cycles and needs no exchange rate".to_string());
+    }
+    if config.min_wei_per_cycle > config.max_wei_per_cycle {
+        return Err("min_wei_per_cycle must not exceed max_wei_per_cycle".to_string());
+    }
+    let state = match get_pricing(&chain) {
+        Some(state) => PricingState { config, ..state },
+        None => PricingState { config, wei_per_cycle: None, updated_at: None, last_error: None },
+    };
+    store_pricing(chain, state);
+    Ok(())
+}
+
+/// Starts the timer that periodically refreshes all exchange rates. Timers do
+/// not survive upgrades, so this needs to be called on init and post_upgrade.
+pub fn start_timer() {
+    set_timer_interval(REFRESH_INTERVAL, || async {
+        for (chain, _) in list_pricing() {
+            refresh(chain).await;
+        }
+    });
+}
+
+/// Fetches the latest price from the configured source and stores the rate.
+async fn refresh(chain: Chain) {
+    let Some(state) = get_pricing(&chain) else {
+        return;
+    };
+    let result = fetch_wei_per_cycle(&state.config.source).await;
+
+    // Re-read the state as the config might have changed in the meantime.
+    let Some(mut state) = get_pricing(&chain) else {
+        return;
+    };
+    match result {
+        Ok(rate) => {
+            let rate = rate * (100 + state.config.margin_percent as u128) / 100;
+            let rate = rate.clamp(state.config.min_wei_per_cycle as u128, state.config.max_wei_per_cycle as u128);
+            state.wei_per_cycle = Some(rate as u64);
+            state.updated_at = Some(ic_cdk::api::time());
+            state.last_error = None;
+        }
+        Err(err) => {
+            ic_cdk::println!("ERROR: Failed to refresh exchange rate for {:?}: {}", chain, err);
+            state.last_error = Some(err);
+        }
+    }
+    store_pricing(chain, state);
+}
+
+/// Returns the market rate without margin or bounds applied.
+async fn fetch_wei_per_cycle(source: &PriceSource) -> Result<u128, String> {
+    match source {
+        PriceSource::Fixed { wei_per_cycle } => Ok(*wei_per_cycle as u128),
+        PriceSource::ExchangeRateCanister { canister_id, symbol } => {
+            let request = GetExchangeRateRequest {
+                base_asset: Asset { symbol: symbol.clone(), class: AssetClass::Cryptocurrency },
+                quote_asset: Asset { symbol: "CXDR".to_string(), class: AssetClass::FiatCurrency },
+                timestamp: None,
+            };
+            // Bounded wait, so that a stalled exchange rate canister can't keep the
+            // canister from stopping for an upgrade.
+            let response = Call::bounded_wait(*canister_id, "get_exchange_rate")
+                .with_arg(&request)
+                .with_cycles(XRC_CALL_CYCLES)
+                .await
+                .map_err(|e| format!("Failed to call exchange rate canister: {}", e))?
+                .candid::<Result<ExchangeRate, Reserved>>()
+                .map_err(|e| format!("Failed to decode exchange rate: {}", e))?
+                .map_err(|_| "Exchange rate canister returned an error".to_string())?;
+            if response.rate == 0 {
+                return Err("Exchange rate is zero".to_string());
+            }
+            // rate is the price of one coin in XDR, scaled by 10^decimals.
+            let scale = 10u128.pow(response.metadata.decimals);
+            Ok(WEI_PER_ETH * scale / (response.rate as u128 * CYCLES_PER_XDR))
+        }
+    }
+}
+
+// Subset of the exchange rate canister interface.
+
+#[derive(CandidType)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(CandidType)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(Deserialize, CandidType)]
+struct ExchangeRate {
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(Deserialize, CandidType)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
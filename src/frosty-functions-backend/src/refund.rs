@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use alloy::primitives::{Address, B256, U256};
+use candid::{CandidType, Nat, Principal};
+use evm_rpc_types::{Hex20, Hex32};
+use ic_cdk::management_canister::{DepositCyclesArgs, deposit_cycles};
+use serde::Deserialize;
+
+use crate::chain::{Chain, EvmChain};
+use crate::evm::TransactionParams;
+use crate::outbox::{Outgoing, enqueue, enqueue_after};
+use crate::runtime::{Job, JobRequest};
+use crate::signer::{Signer, ThresholdSigner};
+use crate::storage::{get_job, update_job};
+
+/// Gas limit of a plain value transfer.
+const TRANSFER_GAS_LIMIT: u64 = 21_000;
+
+/// Refunds smaller than this (after deducting the transaction fee) are not sent.
+const REFUND_DUST_THRESHOLD_WEI: u64 = 10_000_000_000_000;  // 10,000 gwei
+
+/// Number of attempts before giving up on a refund. Failed refunds can still be
+/// retried manually with retry_refund.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for every further retry.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Increase of the max fee per gas for replacement transactions. Nodes only
+/// accept replacements that pay at least 10% more.
+const REPLACEMENT_FEE_BUMP_PERCENT: u128 = 15;
+
+/// Refund of unused gas to the caller of a job.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Refund {
+    /// Amount sent back to the caller, in the native currency of the calling chain.
+    pub amount: Nat,
+    /// Fee deducted for the refund transaction.
+    pub fee: Nat,
+    pub status: RefundStatus,
+    /// Nonce of the refund transaction, recorded before it is broadcast. Retries
+    /// replace the transaction using the same nonce, so that the caller can't be
+    /// refunded twice.
+    pub nonce: Option<u64>,
+    /// Hashes of all transactions signed with the nonce. Any of them might be
+    /// included.
+    pub tx_hashes: Option<Vec<Hex32>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum RefundStatus {
+    /// No refund was sent, e.g. because the remaining gas was below the dust threshold.
+    Skipped(String),
+    /// The refund transaction was signed and is being broadcast.
+    Sending,
+    /// The refund transaction was broadcast.
+    Sent { tx_hash: Hex32 },
+    /// The cycles were deposited to the calling canister.
+    Deposited,
+    /// Sending the refund failed and another attempt is scheduled.
+    Retrying { attempts: u32, error: String },
+    /// Sending the refund failed for good.
+    Failed(String),
+}
+
+impl Refund {
+    fn new(amount: Nat, fee: Nat, status: RefundStatus) -> Self {
+        Self { amount, fee, status, nonce: None, tx_hashes: None }
+    }
+}
+
+/// Queues a refund of the remaining gas for a job that reached a terminal status.
+pub fn queue_refund(request: &JobRequest) {
+    enqueue(Outgoing::Refund(request.clone()));
+}
+
+/// Queues another attempt for a refund that failed for good, e.g. after an
+/// outage of the RPC providers.
+pub fn retry_refund(chain: &Chain, job_id: Nat) -> Result<(), String> {
+    let job = get_job(chain, job_id).ok_or("Job not found")?;
+    match job.refund.as_ref().map(|refund| &refund.status) {
+        Some(RefundStatus::Failed(_)) => {
+            queue_refund(&job.request);
+            Ok(())
+        }
+        _ => Err("Only failed refunds can be retried".to_string()),
+    }
+}
+
+/// Marks the refund of a job as failed after processing it trapped, so that it
+/// can be retried with retry_refund. Called by the outbox.
+pub(crate) fn fail_refund(request: &JobRequest, error: String) {
+    let Some(job) = request.on_chain_id.clone().and_then(|id| get_job(&request.chain, id.into())) else {
+        return;
+    };
+    if matches!(job.refund.as_ref().map(|refund| &refund.status), Some(RefundStatus::Sent { .. } | RefundStatus::Deposited | RefundStatus::Skipped(_))) {
+        return;
+    }
+    update_job(request, |job| {
+        let zero = Nat::from(0u64);
+        let refund = job.refund.get_or_insert_with(|| Refund::new(zero.clone(), zero, RefundStatus::Sending));
+        refund.status = RefundStatus::Failed(error);
+    });
+}
+
+/// Sends the refund of a job and records it. Failed attempts are retried with
+/// exponential backoff. Called by the outbox.
+pub(crate) async fn send_refund(request: &JobRequest) {
+    let Some(job) = request.on_chain_id.clone().and_then(|id| get_job(&request.chain, id.into())) else {
+        ic_cdk::println!("ERROR: Job {:?} to refund not found", request.on_chain_id);
+        return;
+    };
+    // Refunds that were sent already must not be sent again. Refunds that were
+    // interrupted while sending are replaced using the recorded nonce.
+    let attempts = match job.refund.as_ref().map(|refund| &refund.status) {
+        None | Some(RefundStatus::Failed(_)) | Some(RefundStatus::Sending) => 0,
+        Some(RefundStatus::Retrying { attempts, .. }) => *attempts,
+        Some(_) => return,
+    };
+
+    let mut refund = refund_job(&job).await;
+    if let RefundStatus::Failed(err) = &refund.status {
+        let attempts = attempts + 1;
+        if attempts < MAX_ATTEMPTS {
+            ic_cdk::println!("Refund for job {:?} failed, retrying: {}", request.on_chain_id, err);
+            let delay = INITIAL_RETRY_DELAY * 2u32.pow(attempts - 1);
+            refund.status = RefundStatus::Retrying { attempts, error: err.clone() };
+            enqueue_after(Outgoing::Refund(request.clone()), delay);
+        } else {
+            ic_cdk::println!("ERROR: Refund for job {:?} failed after {} attempts: {}", request.on_chain_id, attempts, err);
+        }
+    }
+    update_job(request, |job| job.refund = Some(refund));
+}
+
+async fn refund_job(job: &Job) -> Refund {
+    let remaining = job.remaining_gas();
+    match (&job.request.chain, &job.request.caller) {
+        (Chain::Evm(chain), crate::chain::Address::EvmAddress(caller)) => refund_evm(chain, caller, remaining, job).await,
+        (Chain::Icp, crate::chain::Address::Principal(caller)) => refund_cycles(*caller, remaining).await,
+        _ => Refund::new(
+            Nat::from(0u64),
+            Nat::from(0u64),
+            RefundStatus::Skipped("Caller address does not match the calling chain".to_string()),
+        ),
+    }
+}
+
+/// Sends the remaining gas minus the transaction fee back to the caller, using
+/// the canister's main EVM account which received the gas payment. If an
+/// earlier attempt recorded a nonce, its transactions are checked first and
+/// then replaced with a higher fee, as they might still be included.
+async fn refund_evm(chain: &EvmChain, caller: &Hex20, remaining: Nat, job: &Job) -> Refund {
+    let zero = Nat::from(0u64);
+    let previous = job.refund.clone().unwrap_or_else(|| Refund::new(zero.clone(), zero.clone(), RefundStatus::Sending));
+    let mut nonce = previous.nonce;
+    let mut tx_hashes = previous.tx_hashes.clone().unwrap_or_default();
+    let record = |status: RefundStatus, nonce: Option<u64>, tx_hashes: &[Hex32]| Refund {
+        status,
+        nonce,
+        tx_hashes: Some(tx_hashes.to_vec()),
+        ..previous.clone()
+    };
+
+    let signer = ThresholdSigner::new(vec![]);
+    let from = match signer.eth_address() {
+        Ok(from) => from,
+        Err(err) => return record(RefundStatus::Failed(err), nonce, &tx_hashes),
+    };
+    let mut min_fee_per_gas = 0;
+    if let Some(previous_nonce) = nonce {
+        for tx_hash in &tx_hashes {
+            match crate::evm::transaction_receipt(chain, B256::from_slice(tx_hash.as_ref())).await {
+                Ok(Some(_)) => return record(RefundStatus::Sent { tx_hash: tx_hash.clone() }, nonce, &tx_hashes),
+                Ok(None) => {}
+                Err(err) => return record(RefundStatus::Failed(err), nonce, &tx_hashes),
+            }
+        }
+        match crate::evm::transaction_count(chain, from, false).await {
+            // The nonce was used by another transaction, so none of the earlier
+            // refund transactions can be included anymore.
+            Ok(count) if count > previous_nonce => {
+                nonce = None;
+                tx_hashes.clear();
+            }
+            Ok(_) => {
+                let previous_fee_per_gas = u128::try_from(previous.fee.0.clone()).unwrap_or(0) / TRANSFER_GAS_LIMIT as u128;
+                min_fee_per_gas = previous_fee_per_gas * (100 + REPLACEMENT_FEE_BUMP_PERCENT) / 100;
+            }
+            Err(err) => return record(RefundStatus::Failed(err), nonce, &tx_hashes),
+        }
+    }
+
+    let fees = match crate::evm::estimate_fees(chain).await {
+        Ok(fees) => fees,
+        Err(err) => return record(RefundStatus::Failed(err), nonce, &tx_hashes),
+    };
+    let max_fee_per_gas = fees.max_fee_per_gas.max(min_fee_per_gas);
+    let fee = Nat::from(max_fee_per_gas) * TRANSFER_GAS_LIMIT;
+    if remaining < fee.clone() + REFUND_DUST_THRESHOLD_WEI {
+        // An earlier transaction might still be included, so the refund can't
+        // be skipped.
+        if !tx_hashes.is_empty() {
+            return record(RefundStatus::Failed("Remaining gas does not cover the replacement fee".to_string()), nonce, &tx_hashes);
+        }
+        return Refund::new(zero, fee, RefundStatus::Skipped("Remaining gas below dust threshold".to_string()));
+    }
+    let amount = remaining - fee.clone();
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => match crate::evm::transaction_count(chain, from, true).await {
+            Ok(nonce) => nonce,
+            Err(err) => return record(RefundStatus::Failed(err), None, &tx_hashes),
+        },
+    };
+
+    let replacement = !tx_hashes.is_empty();
+    let params = TransactionParams {
+        to: Address::from_slice(caller.as_ref()),
+        value: U256::from_be_slice(&amount.0.to_bytes_be()),
+        data: Vec::new(),
+        gas_limit: TRANSFER_GAS_LIMIT,
+        max_fee_per_gas,
+        nonce: Some(nonce),
+        // The priority fee must be bumped as well for replacements, and the one
+        // of the replaced transaction is at most its max fee.
+        max_priority_fee_per_gas: replacement.then_some(max_fee_per_gas),
+    };
+    let (tx_hash, raw_tx) = match crate::evm::build_transaction(chain, &signer, params).await {
+        Ok(signed) => signed,
+        Err(err) => return record(RefundStatus::Failed(err), Some(nonce), &tx_hashes),
+    };
+    // Record the transaction before broadcasting, as it might be included even
+    // if broadcasting fails or traps.
+    tx_hashes.push(tx_hash.0.into());
+    let refund = Refund {
+        amount,
+        fee,
+        status: RefundStatus::Sending,
+        nonce: Some(nonce),
+        tx_hashes: Some(tx_hashes),
+    };
+    update_job(&job.request, |job| job.refund = Some(refund.clone()));
+    let status = match crate::evm::send_raw_transaction(chain, raw_tx).await {
+        Ok(tx_hash) => RefundStatus::Sent { tx_hash: tx_hash.0.into() },
+        Err(err) => RefundStatus::Failed(err),
+    };
+    Refund { status, ..refund }
+}
+
+/// Deposits the remaining cycles back to the calling canister. Only canisters
+/// can attach cycles, so ICP callers are always canisters.
+async fn refund_cycles(caller: Principal, remaining: Nat) -> Refund {
+    let fee = Nat::from(0u64);
+    let Ok(cycles) = u128::try_from(remaining.0.clone()) else {
+        return Refund::new(remaining, fee, RefundStatus::Failed("Refund exceeds u128".to_string()));
+    };
+    if cycles == 0 {
+        return Refund::new(remaining, fee, RefundStatus::Skipped("No cycles remaining".to_string()));
+    }
+    let status = match deposit_cycles(&DepositCyclesArgs { canister_id: caller }, cycles).await {
+        Ok(()) => RefundStatus::Deposited,
+        Err(err) => RefundStatus::Failed(format!("Failed to deposit cycles: {}", err)),
+    };
+    Refund::new(remaining, fee, status)
+}
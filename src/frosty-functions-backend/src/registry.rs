@@ -0,0 +1,121 @@
+use candid::CandidType;
+use evm_rpc_types::Hex20;
+use serde::Deserialize;
+
+use crate::chain::EvmChain;
+use crate::storage::{delete_chain_config, get_chain_config, get_consensus_threshold, list_chain_configs, store_chain_config};
+
+/// Chains that are known without a registry entry.
+const BUILTIN_CHAINS: [EvmChain; 7] = [
+    EvmChain::EthereumMainnet,
+    EvmChain::EthereumSepolia,
+    EvmChain::ArbitrumOne,
+    EvmChain::ArbitrumSepolia,
+    EvmChain::Base,
+    EvmChain::Optimism,
+    EvmChain::Localhost,
+];
+
+/// Configuration of an EVM chain. Built-in chains come with defaults that can
+/// be overridden in the registry, custom chains need a registry entry.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ChainConfig {
+    /// EIP-155 chain ID.
+    pub chain_id: u64,
+    /// Address of the Bridge contract. Chains without a bridge are not indexed.
+    /// No Bridge is deployed on Ethereum, Base and Optimism yet, so their
+    /// address needs to be set in the registry before indexing them.
+    pub bridge_address: Option<Hex20>,
+    /// JSON-RPC endpoints. If empty, the built-in providers of the chain are used.
+    pub rpc_urls: Vec<String>,
+    /// Number of blocks after which a block is considered final. If None, the
+    /// chain's `finalized` block tag is used instead.
+    pub finality_depth: Option<u64>,
+    pub is_testnet: bool,
+    /// Disabled chains are not indexed.
+    pub enabled: bool,
+}
+
+/// Returns the configuration of the given chain: The registry entry if there
+/// is one, otherwise the built-in defaults.
+pub fn chain_config(chain: &EvmChain) -> Option<ChainConfig> {
+    get_chain_config(chain).or_else(|| builtin_config(chain))
+}
+
+pub fn is_enabled(chain: &EvmChain) -> bool {
+    chain_config(chain).is_some_and(|config| config.enabled)
+}
+
+/// Returns the configuration of all built-in and registered chains.
+pub fn list_chains() -> Vec<(EvmChain, ChainConfig)> {
+    let mut chains: Vec<(EvmChain, ChainConfig)> = BUILTIN_CHAINS.iter()
+        .filter_map(|chain| chain_config(chain).map(|config| (chain.clone(), config)))
+        .collect();
+    chains.extend(list_chain_configs().into_iter().filter(|(chain, _)| matches!(chain, EvmChain::Custom(_))));
+    chains
+}
+
+/// Adds or updates a chain in the registry. Takes effect right away. Fails if the
+/// new RPC endpoints are fewer than the chain's configured consensus threshold.
+pub fn set_chain_config(chain: EvmChain, config: ChainConfig) -> Result<(), String> {
+    if config.chain_id != chain.chain_id() {
+        return Err(format!("Chain ID {} does not match {:?}", config.chain_id, chain));
+    }
+    if let EvmChain::Custom(chain_id) = chain {
+        if let Some(builtin) = BUILTIN_CHAINS.iter().find(|builtin| builtin.chain_id() == chain_id) {
+            return Err(format!("Chain ID {} belongs to the built-in chain {:?}", chain_id, builtin));
+        }
+        if config.rpc_urls.is_empty() {
+            return Err("Custom chains need at least one RPC endpoint".to_string());
+        }
+    }
+    if let Some(threshold) = get_consensus_threshold(&chain) {
+        if !config.rpc_urls.is_empty() && (config.rpc_urls.len() as u32) < threshold {
+            return Err(format!(
+                "{} RPC endpoints are fewer than the consensus threshold of {}. Lower it with set_consensus_threshold first",
+                config.rpc_urls.len(), threshold
+            ));
+        }
+    }
+    store_chain_config(chain, config);
+    Ok(())
+}
+
+/// Removes a chain from the registry. Built-in chains fall back to their defaults.
+pub fn remove_chain_config(chain: &EvmChain) -> Result<(), String> {
+    delete_chain_config(chain)
+        .map(|_| ())
+        .ok_or_else(|| format!("No registry entry for {:?}", chain))
+}
+
+fn builtin_config(chain: &EvmChain) -> Option<ChainConfig> {
+    let (bridge_address, rpc_urls, finality_depth, is_testnet) = match chain {
+        EvmChain::EthereumMainnet => (None, vec![], None, false),
+        EvmChain::EthereumSepolia => (None, vec![], None, true),
+        EvmChain::ArbitrumOne => (Some("0xe712A7e50abA019A6d225584583b09C4265B037B"), vec![], None, false),
+        EvmChain::ArbitrumSepolia => (
+            Some("0xcAcbb4E46F2a68e3d178Fb98dCaCe59d12d54CBc"),
+            vec![
+                "https://arbitrum-sepolia-rpc.publicnode.com",
+                "https://sepolia-rollup.arbitrum.io/rpc",
+                "https://arbitrum-sepolia.drpc.org",
+            ],
+            None,
+            true,
+        ),
+        EvmChain::Base => (None, vec![], None, false),
+        EvmChain::Optimism => (None, vec![], None, false),
+        EvmChain::Localhost => (Some("0x9fE46736679d2D9a65F0992F2272dE9f3c7fa6e0"), vec!["http://127.0.0.1:8545"], Some(0), true),
+        EvmChain::Custom(_) => return None,
+    };
+    Some(ChainConfig {
+        chain_id: chain.chain_id(),
+        bridge_address: bridge_address.map(|address| {
+            address.parse::<alloy::primitives::Address>().unwrap().0.0.into()
+        }),
+        rpc_urls: rpc_urls.into_iter().map(String::from).collect(),
+        finality_depth,
+        is_testnet,
+        enabled: true,
+    })
+}
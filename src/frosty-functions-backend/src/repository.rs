@@ -1,9 +1,18 @@
 use alloy::primitives::{FixedBytes, keccak256};
 use candid::{CandidType};
+use evm_rpc_types::Nat256;
 use serde::Deserialize;
 
+use crate::chain::{Address, Chain, EvmChain};
+use crate::runtime::{DEFAULT_MEMORY_LIMIT, FailureReason, JobRequest, MAX_MEMORY_LIMIT, validate_module};
 use crate::storage::{get_function, store_function};
 
+/// Maximum size of a function's WASM binary.
+const MAX_BINARY_SIZE: usize = 1024 * 1024;
+
+/// Maximum size of a function's source code.
+const MAX_SOURCE_SIZE: usize = 512 * 1024;
+
 pub type FunctionId = Vec<u8>; // Keccak256 hash (32 bytes) of the function binary.
 
 #[derive(Clone, CandidType, Debug, Deserialize)]
@@ -18,7 +27,31 @@ pub struct FunctionState {
     pub definition: FunctionDefinition,
     pub hash: FunctionId,
     pub deployed_at: u64,  // Timestamp in nanoseconds
+    /// Whether the smoke simulation during deployment succeeded. Functions whose
+    /// simulation fails in user code, e.g. because they require calldata, are
+    /// still deployed unverified.
     pub is_verified: bool,
+    /// Checks run during deployment. Not set for functions deployed before
+    /// deploy-time validation was introduced.
+    pub validation: Option<ValidationReport>,
+    /// Linear memory limit (bytes) enforced for executions. Not set for functions
+    /// deployed before memory limits were introduced, which use the default.
+    pub memory_limit: Option<u64>,
+}
+
+/// Result of the checks run when deploying a function.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ValidationReport {
+    /// Imports of the WASM module, e.g. "❄️.calldata".
+    pub imports: Vec<String>,
+    /// Error of the smoke simulation with empty calldata, if it failed. Functions
+    /// that require calldata are expected to fail here, so this only prevents
+    /// deployment if the module is invalid or runs out of memory.
+    pub simulation_error: Option<String>,
+    /// Fees charged in the smoke simulation.
+    pub simulation_fees: u64,
+    /// Number of commits made in the smoke simulation.
+    pub simulation_commits: u64,
 }
 
 #[derive(CandidType, Debug)]
@@ -28,20 +61,78 @@ pub enum DeployResult {
     Error(String),
 }
 
-pub fn deploy_function(definition: FunctionDefinition) -> DeployResult {
+/// Deploys a function whose executions are limited to `memory_limit` bytes of
+/// linear memory, or DEFAULT_MEMORY_LIMIT if not set.
+pub fn deploy_function(definition: FunctionDefinition, memory_limit: Option<u64>) -> DeployResult {
     let id = keccak256(&definition.binary).to_vec();
     if get_function(id.clone()).is_some() {
         return DeployResult::Duplicate(id);
     }
 
-    // TODO: Run a simulation to verify integrity before storing.
+    let memory_limit = memory_limit.unwrap_or(DEFAULT_MEMORY_LIMIT);
+    if memory_limit > MAX_MEMORY_LIMIT {
+        return DeployResult::Error(format!("Memory limit must not exceed {} bytes", MAX_MEMORY_LIMIT));
+    }
+
+    let validation = match validate(&id, &definition, memory_limit) {
+        Ok(validation) => validation,
+        Err(err) => return DeployResult::Error(err),
+    };
 
     store_function(id.clone(), FunctionState {
         definition,
         hash: id.clone(),
         deployed_at: ic_cdk::api::time(),
-        is_verified: false,
+        is_verified: validation.simulation_error.is_none(),
+        validation: Some(validation),
+        memory_limit: Some(memory_limit),
     });
 
     DeployResult::Success(id)
 }
+
+/// Validates the WASM module and runs a smoke simulation with empty calldata.
+/// Returns an error if the function can't be deployed.
+fn validate(id: &FunctionId, definition: &FunctionDefinition, memory_limit: u64) -> Result<ValidationReport, String> {
+    if definition.binary.len() > MAX_BINARY_SIZE {
+        return Err(format!("WASM binary must not exceed {} bytes", MAX_BINARY_SIZE));
+    }
+    if definition.source.len() > MAX_SOURCE_SIZE {
+        return Err(format!("Source code must not exceed {} bytes", MAX_SOURCE_SIZE));
+    }
+    let imports = validate_module(&definition.binary)?;
+
+    let request = JobRequest {
+        chain: Chain::Evm(EvmChain::Localhost),
+        block_hash: None,
+        block_number: None,
+        transaction_hash: None,
+        on_chain_id: Some(Nat256::from_be_bytes([0u8; 32])),
+        caller: Address::EvmAddress([0u8; 20].into()),
+        function_hash: id.clone(),
+        data: Vec::new(),
+        gas_payment: Nat256::from_be_bytes([0u8; 32]),
+        callback: None,
+    };
+    let (simulation_error, simulation_fees, simulation_commits) =
+        match crate::simulation::run_simulation(request, &definition.binary, memory_limit) {
+            Ok((result, failure)) => {
+                let error = failure.map(rejected_by_smoke_simulation).transpose()?;
+                (error, result.job.total_cost(), result.commits.len() as u64)
+            }
+            Err(reason) => (Some(rejected_by_smoke_simulation(reason)?), 0, 0),
+        };
+    Ok(ValidationReport { imports, simulation_error, simulation_fees, simulation_commits })
+}
+
+/// Returns the error of a failed smoke simulation to record in the validation
+/// report, or an error if the failure shows that the module can't run at all
+/// rather than rejecting the empty calldata.
+fn rejected_by_smoke_simulation(reason: FailureReason) -> Result<String, String> {
+    match reason {
+        FailureReason::InvalidModule(_) | FailureReason::OutOfMemory { .. } => {
+            Err(format!("Smoke simulation failed: {}", reason))
+        }
+        reason => Ok(reason.to_string()),
+    }
+}
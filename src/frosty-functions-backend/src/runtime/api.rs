@@ -1,14 +1,17 @@
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{Ref, RefMut};
 use std::env;
-use std::rc::Rc;
+use std::time::Duration;
 
-use alloy::primitives::{Address, keccak256};
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::{Address, B256, U256, keccak256};
+use alloy::rpc::types::Filter;
 use ic_stable_structures::Storable;
 use wasmi::{Caller, Error, Func, Global, Linker, Memory, Mutability, Store, Val, errors::LinkerError};
 use crate::runtime::{LogEntry, LogType, RuntimeEnvironment, job};
 use crate::signer::{Signer, SimulationSigner, ThresholdSigner, derivation_path_for_caller, derivation_path_for_function};
 use crate::{Chain};
-use crate::runtime::runtime::{ExecutionContext};
+use crate::chain::EvmChain;
+use crate::runtime::runtime::{ExecutionContext, StoreData};
 
 /// The maximum length of data that can be passed to/from the guest.
 // TODO: Consider increasing if there is a use case.
@@ -17,26 +20,66 @@ const BUFFER_MAX_LEN: usize = 10_000_000;
 /// The maximum length of console log messages.
 const CONSOLE_LOG_MAX_LEN: usize = 10_000;
 
+/// The maximum length of a job result.
+const RESULT_MAX_LEN: usize = 64 * 1024;
+
 // Constants used in simulations.
 const SIMULATION_ADDRESS: &str = "0x1234567890abcdef1234567890abcdef12345678";
+const SIMULATION_EVM_CALL_RESULT: [u8; 32] = [0u8; 32];
 
 const CYCLES_RAW_RAND: u64 = 5_400_000;
 
+/// Cost of a host function whose work grows with the size of its input.
+struct HostCallCost {
+    base: u64,
+    per_byte: u64,
+}
+
+impl HostCallCost {
+    fn cycles(&self, bytes: usize) -> u64 {
+        self.base + self.per_byte * bytes as u64
+    }
+}
+
+// Log messages are kept in stable memory as part of the commit.
+const COST_CONSOLE_LOG: HostCallCost = HostCallCost { base: 10_000, per_byte: 100 };
+const COST_CRYPTO_KECCAK256: HostCallCost = HostCallCost { base: 10_000, per_byte: 50 };
+// Results are stored in stable memory as part of the job.
+const COST_SET_RESULT: HostCallCost = HostCallCost { base: 10_000, per_byte: 100 };
+// Copying data into the guest memory.
+const COST_COPY_BUFFER: HostCallCost = HostCallCost { base: 1_000, per_byte: 2 };
+
 // Signing on ICP is quite expensive, see https://docs.internetcomputer.org/references/t-sigs-how-it-works/#fees-for-the-t-ecdsa-production-key
 // TODO: Add costs for the cansiter call, which depends on the length of the message.
 const CYCLES_SIGN_MESSAGE: u64 = 26_153_846_153;
+// Public keys are derived locally, which still involves elliptic curve operations.
+const CYCLES_DERIVE_PUBLIC_KEY: u64 = 1_000_000;  // TODO: Calculate exact value.
 const CYCLES_EVM_RPC_CALL: u64 = 1_000_000_000;  // TODO: Calculate exact value.
+// HTTP outcalls are charged by the maximum response size, see https://internetcomputer.org/docs/references/cycles-cost-formulas#https-outcalls
+const CYCLES_PER_RPC_RESPONSE_BYTE: u64 = 800;
+
+/// The maximum number of blocks that can be queried with a single evm_get_logs call.
+const GET_LOGS_MAX_BLOCK_RANGE: u64 = 1_000;
+
+/// The maximum size of the encoded evm_get_logs response.
+const GET_LOGS_MAX_RESPONSE_BYTES: usize = 1_000_000;
+
+// Timers are cheap on the IC, but we still charge for keeping the job alive.
+const CYCLES_SET_TIMER: u64 = 1_000_000;  // TODO: Calculate exact value.
+
+/// The maximum delay of a single timer.
+const TIMER_MAX_DELAY_MS: u64 = 24 * 60 * 60 * 1000;
 
 const SIGNER_FOR_CALLER: i32 = 0;
 const SIGNER_FOR_FUNCTION: i32 = 1;
 
 // TODO: Simplify this. Get rid of all the macros.
 // TODO: Maybe move the Rc into an ExecutionContextInner.
-pub type Ctx = Rc<RefCell<ExecutionContext>>;
+pub type Ctx = StoreData;
 
 macro_rules! ctx {
     ($caller:expr) => {
-        $caller.data_mut().borrow_mut() as std::cell::RefMut<'_, ExecutionContext>
+        $caller.data_mut().ctx.borrow_mut() as std::cell::RefMut<'_, ExecutionContext>
     };
 }
 
@@ -78,14 +121,19 @@ pub fn register_host_functions(linker: &mut Linker<Ctx>, store: &mut Store<Ctx>)
     register!(calldata, linker, store);
     register!(copy_shared_buffer, linker, store);
     register!(on_chain_id, linker, store);
+    register!(set_result, linker, store);
 
     register!(signer_public_key, linker, store);
     register!(signer_eth_address, linker, store);
     register!(sign_with_ecdsa, linker, store);
+    register!(signer_send_transaction, linker, store);
     register!(crypto_keccak256, linker, store);
     register!(evm_chain_id, linker, store);
+    register!(evm_call, linker, store);
+    register!(evm_get_logs, linker, store);
 
     register!(ic_raw_rand, linker, store);
+    register!(set_timer, linker, store);
 
     Ok(())
 }
@@ -100,18 +148,20 @@ fn seed() -> Result<f64, Error> {
 }
 
 // TODO: Support console.error etc.
-fn console_log(mut caller: Caller<Ctx>, message_ptr: i32) {
+fn console_log(mut caller: Caller<Ctx>, message_ptr: i32) -> Result<(), Error> {
+    let len = read_buffer_len(&caller, message_ptr)?.min(CONSOLE_LOG_MAX_LEN * 2);
+    ctx!(caller).charge_host_call("console_log", COST_CONSOLE_LOG.cycles(len))?;
     let message = read_utf16_string(&caller, message_ptr, CONSOLE_LOG_MAX_LEN)
         // TODO: Return error?
         .unwrap_or_else(|e| format!("(failed to read log message: {})", e));
     ctx!(caller).commit_context().logs.push(LogEntry { level: LogType::Default, message: message.clone() });
-    // TODO: Charge cycles for logs storage.
-    // TODO: Limit log size?
+    Ok(())
 }
 
 /// Writes the calldata into the provided buffer, which is expected to be of CALLDATA_SIZE.
 fn calldata(mut caller: Caller<Ctx>, buffer_ptr: i32) -> Result<(), Error> {
     let calldata = job!(caller).data.clone();
+    ctx!(caller).charge_host_call("calldata", COST_COPY_BUFFER.cycles(calldata.len()))?;
     get_memory(&caller).write(&mut caller, buffer_ptr as usize, &calldata)?;
     Ok(())  // TODO: remove?
 }
@@ -126,7 +176,20 @@ fn on_chain_id(mut caller: Caller<Ctx>) -> i64 {
     }
 }
 
+/// Stores the given buffer as the result of the job, replacing any previous result.
+fn set_result(mut caller: Caller<Ctx>, result_ptr: i32) -> Result<(), Error> {
+    let len = read_buffer_len(&caller, result_ptr)?;
+    if len > RESULT_MAX_LEN {
+        return Err(Error::new(format!("Result length {} exceeds maximum allowed {}", len, RESULT_MAX_LEN)));
+    }
+    ctx!(caller).charge_host_call("set_result", COST_SET_RESULT.cycles(len))?;
+    let result = read_buffer(&caller, result_ptr, RESULT_MAX_LEN)?;
+    ctx!(caller).env_mut().set_result(result);
+    Ok(())
+}
+
 fn signer_public_key(mut caller: Caller<Ctx>, signer_type: i32, signer_derivation: i32, buffer_ptr: i32) -> Result<(), Error> {
+    ctx!(caller).charge_host_call("signer_public_key", CYCLES_DERIVE_PUBLIC_KEY)?;
     let signer = get_signer(&caller, signer_type, signer_derivation)?;
     let public_key = signer.public_key().map_err(|e| Error::new(e))?;
     get_memory(&caller).write(&mut caller, buffer_ptr as usize, &public_key)?;
@@ -134,6 +197,7 @@ fn signer_public_key(mut caller: Caller<Ctx>, signer_type: i32, signer_derivatio
 }
 
 fn signer_eth_address(mut caller: Caller<Ctx>, signer_type: i32, signer_derivation: i32, buffer_ptr: i32) -> Result<(), Error> {
+    ctx!(caller).charge_host_call("signer_eth_address", CYCLES_DERIVE_PUBLIC_KEY)?;
     let signer = get_signer(&caller, signer_type, signer_derivation)?;
     let address = signer.eth_address().map_err(|e| Error::new(e))?;
     get_memory(&caller).write(&mut caller, buffer_ptr as usize, &address.to_bytes())?;
@@ -141,7 +205,7 @@ fn signer_eth_address(mut caller: Caller<Ctx>, signer_type: i32, signer_derivati
 }
 
 fn sign_with_ecdsa(mut caller: Caller<Ctx>, signer_type: i32, signer_derivation: i32, message_ptr: i32, promise_id: i32) -> Result<(), Error> {
-    ctx!(caller).charge_cycles(CYCLES_SIGN_MESSAGE)?;
+    ctx!(caller).charge_host_call("sign_with_ecdsa", CYCLES_SIGN_MESSAGE)?;
     let msg_hash = read_buffer(&caller, message_ptr, 33)?;
     if msg_hash.len() != 32 {
         return Err(Error::new(format!("Invalid message hash length: {}", msg_hash.len())));
@@ -159,8 +223,78 @@ fn sign_with_ecdsa(mut caller: Caller<Ctx>, signer_type: i32, signer_derivation:
     Ok(())
 }
 
+/// Sends an EIP-1559 transaction from the given signer on the calling chain and
+/// resolves the promise with the transaction hash. The signer's wallet pays for
+/// the transaction on-chain, so only the signing and RPC calls are charged from
+/// the job's gas payment. The wallet's balance is not checked upfront; if it
+/// can't cover the fees, the RPC node rejects the transaction and the promise
+/// is rejected with its error.
+fn signer_send_transaction(
+    mut caller: Caller<Ctx>,
+    signer_type: i32,
+    signer_derivation: i32,
+    to_ptr: i32,
+    value_ptr: i32,
+    data_ptr: i32,
+    gas_limit: u64,
+    max_fee_per_gas: u64,
+    promise_id: i32,
+) -> Result<(), Error> {
+    let chain = match job!(caller).chain.clone() {
+        Chain::Evm(chain) => chain,
+        _ => return Err(Error::new("Transactions are only supported on EVM chains")),
+    };
+    let to = read_buffer(&caller, to_ptr, 20)?;
+    if to.len() != 20 {
+        return Err(Error::new(format!("Invalid address length: {}", to.len())));
+    }
+    let value = if value_ptr != 0 {
+        let value = read_buffer(&caller, value_ptr, 32)?;
+        U256::try_from_be_slice(&value)
+            .ok_or_else(|| Error::new("Invalid transaction value"))?
+    } else {
+        U256::ZERO
+    };
+    let data = if data_ptr != 0 {
+        read_buffer(&caller, data_ptr, BUFFER_MAX_LEN)?
+    } else {
+        Vec::new()
+    };
+    let signer = get_signer(&caller, signer_type, signer_derivation)?;
+    ctx!(caller).charge_host_call("signer_send_transaction", CYCLES_SIGN_MESSAGE + 3 * CYCLES_EVM_RPC_CALL)?;
+
+    let params = crate::evm::TransactionParams {
+        to: Address::from_slice(&to),
+        value,
+        data,
+        gas_limit,
+        max_fee_per_gas: max_fee_per_gas as u128,
+        nonce: None,
+        max_priority_fee_per_gas: None,
+    };
+    let is_simulation = env!(caller).is_simulation();
+    ctx!(caller).queue_task(
+        promise_id,
+        format!("send_transaction(to={}, value={})", params.to, params.value),
+        Box::pin(async move {
+            if !is_simulation {
+                let tx_hash = crate::evm::send_transaction(&chain, &*signer, params).await?;
+                Ok(tx_hash.to_vec())
+            } else {
+                // Simulations don't broadcast anything, so we return a fake hash
+                // derived from the transaction parameters.
+                let mut preimage = params.to.to_vec();
+                preimage.extend_from_slice(&params.value.to_be_bytes::<32>());
+                preimage.extend_from_slice(&params.data);
+                Ok(keccak256(&preimage).to_vec())
+            }
+        })
+    );
+    Ok(())
+}
+
 fn get_signer(caller: &Caller<Ctx>, signer_type: i32, signer_derivation: i32) -> Result<Box<dyn Signer>, Error> {
-    let job = caller.data().borrow().env().job_request().clone();
+    let job = caller.data().ctx.borrow().env().job_request().clone();
     let extra_derivation = if signer_derivation != 0 {
         Some(read_buffer(&caller, signer_derivation, 1024)?)
     } else {
@@ -180,7 +314,7 @@ fn get_signer(caller: &Caller<Ctx>, signer_type: i32, signer_derivation: i32) ->
         ),
         _ => return Err(Error::new(format!("Invalid signer type: {}", signer_type))),
     };
-    if caller.data().borrow().env().is_simulation() {
+    if caller.data().ctx.borrow().env().is_simulation() {
         Ok(Box::new(SimulationSigner::new(derivation_path)))
     } else {
         Ok(Box::new(ThresholdSigner::new(derivation_path)))
@@ -188,6 +322,8 @@ fn get_signer(caller: &Caller<Ctx>, signer_type: i32, signer_derivation: i32) ->
 }
 
 fn crypto_keccak256(mut caller: Caller<Ctx>, message_ptr: i32, buffer_ptr: i32) -> Result<(), Error> {
+    let len = read_buffer_len(&caller, message_ptr)?;
+    ctx!(caller).charge_host_call("crypto_keccak256", COST_CRYPTO_KECCAK256.cycles(len))?;
     let data = read_buffer(&caller, message_ptr, BUFFER_MAX_LEN)?;
     let hash = keccak256(&data);
     ic_cdk::println!("Keccak256 input: 0x{}", hex::encode(&data));
@@ -203,8 +339,116 @@ fn evm_chain_id(mut caller: Caller<Ctx>) -> u64 {
     }
 }
 
+/// Executes a read-only call (eth_call) against a contract on the given EVM chain
+/// and resolves the promise with the raw return data.
+fn evm_call(mut caller: Caller<Ctx>, chain_id: u64, address_ptr: i32, calldata_ptr: i32, promise_id: i32) -> Result<(), Error> {
+    ctx!(caller).charge_host_call("evm_call", CYCLES_EVM_RPC_CALL)?;
+    let chain = EvmChain::from_chain_id(chain_id)
+        .ok_or_else(|| Error::new(format!("Unsupported EVM chain ID: {}", chain_id)))?;
+    let address = read_buffer(&caller, address_ptr, 20)?;
+    if address.len() != 20 {
+        return Err(Error::new(format!("Invalid address length: {}", address.len())));
+    }
+    let address = Address::from_slice(&address);
+    let calldata = read_buffer(&caller, calldata_ptr, BUFFER_MAX_LEN)?;
+    let is_simulation = env!(caller).is_simulation();
+    ctx!(caller).queue_task(
+        promise_id,
+        format!("eth_call({:?}, {})", chain, address),
+        Box::pin(async move {
+            if !is_simulation {
+                crate::evm::eth_call(&chain, address, calldata).await
+            } else {
+                // Simulations can't make HTTP outcalls, so we return a single
+                // zero word, which decodes as the default value for most return types.
+                Ok(SIMULATION_EVM_CALL_RESULT.to_vec())
+            }
+        })
+    );
+    Ok(())
+}
+
+/// Fetches logs (eth_getLogs) from the given EVM chain and resolves the promise with
+/// the logs in the binary format described in `evm::encode_logs`.
+///
+/// The topics buffer contains up to four 32 byte topics. A topic of all zeros acts as
+/// a wildcard. The caller pays for max_response_bytes upfront, which is passed on
+/// as the response size limit of the RPC call. The promise is rejected if the
+/// response is larger than that.
+fn evm_get_logs(
+    mut caller: Caller<Ctx>,
+    chain_id: u64,
+    address_ptr: i32,
+    topics_ptr: i32,
+    from_block: u64,
+    to_block: u64,
+    max_response_bytes: i32,
+    promise_id: i32,
+) -> Result<(), Error> {
+    let chain = EvmChain::from_chain_id(chain_id)
+        .ok_or_else(|| Error::new(format!("Unsupported EVM chain ID: {}", chain_id)))?;
+    if to_block < from_block || to_block - from_block >= GET_LOGS_MAX_BLOCK_RANGE {
+        return Err(Error::new(format!("Invalid block range {}..={}. At most {} blocks can be queried at once", from_block, to_block, GET_LOGS_MAX_BLOCK_RANGE)));
+    }
+    let max_response_bytes = max_response_bytes as u32 as usize;
+    if max_response_bytes > GET_LOGS_MAX_RESPONSE_BYTES {
+        return Err(Error::new(format!("max_response_bytes exceeds maximum allowed {}", GET_LOGS_MAX_RESPONSE_BYTES)));
+    }
+    ctx!(caller).charge_host_call("evm_get_logs", CYCLES_EVM_RPC_CALL + max_response_bytes as u64 * CYCLES_PER_RPC_RESPONSE_BYTE)?;
+
+    let mut filter = Filter::new()
+        .from_block(BlockNumberOrTag::Number(from_block))
+        .to_block(BlockNumberOrTag::Number(to_block));
+    if address_ptr != 0 {
+        let address = read_buffer(&caller, address_ptr, 20)?;
+        if address.len() != 20 {
+            return Err(Error::new(format!("Invalid address length: {}", address.len())));
+        }
+        filter = filter.address(Address::from_slice(&address));
+    }
+    if topics_ptr != 0 {
+        let topics = read_buffer(&caller, topics_ptr, 4 * 32)?;
+        if topics.len() % 32 != 0 {
+            return Err(Error::new(format!("Invalid topics length: {}", topics.len())));
+        }
+        for (i, topic) in topics.chunks_exact(32).enumerate() {
+            let topic = B256::from_slice(topic);
+            if topic.is_zero() {
+                continue;
+            }
+            filter = match i {
+                0 => filter.event_signature(topic),
+                1 => filter.topic1(topic),
+                2 => filter.topic2(topic),
+                _ => filter.topic3(topic),
+            };
+        }
+    }
+
+    let is_simulation = env!(caller).is_simulation();
+    ctx!(caller).queue_task(
+        promise_id,
+        format!("eth_getLogs({:?}, blocks {}..={})", chain, from_block, to_block),
+        Box::pin(async move {
+            let logs = if !is_simulation {
+                crate::evm::get_logs(&chain, &filter, max_response_bytes as u64).await
+                    .map_err(|e| format!("eth_getLogs failed: {}", e))?
+            } else {
+                // Simulations can't make HTTP outcalls, so no logs are returned.
+                Vec::new()
+            };
+            let encoded = crate::evm::encode_logs(&logs);
+            if encoded.len() > max_response_bytes {
+                return Err(format!("Response size of {} bytes exceeds max_response_bytes of {}", encoded.len(), max_response_bytes));
+            }
+            Ok(encoded)
+        })
+    );
+    Ok(())
+}
+
 fn ic_raw_rand(mut caller: Caller<Ctx>, promise_id: i32) -> Result<(), Error> {
-    ctx!(caller).charge_cycles(CYCLES_RAW_RAND)?;
+    ctx!(caller).charge_host_call("ic_raw_rand", CYCLES_RAW_RAND)?;
     let is_simulation = env!(caller).is_simulation();
     ctx!(caller).queue_task(
         promise_id,
@@ -224,6 +468,26 @@ fn ic_raw_rand(mut caller: Caller<Ctx>, promise_id: i32) -> Result<(), Error> {
     Ok(())
 }
 
+/// Resolves the promise with an empty buffer after the given delay. Timers are
+/// not persisted, so jobs waiting for a timer fail if the canister is upgraded
+/// in the meantime.
+fn set_timer(mut caller: Caller<Ctx>, delay_ms: u64, promise_id: i32) -> Result<(), Error> {
+    if delay_ms > TIMER_MAX_DELAY_MS {
+        return Err(Error::new(format!("Timer delay of {}ms exceeds maximum allowed {}ms", delay_ms, TIMER_MAX_DELAY_MS)));
+    }
+    ctx!(caller).charge_host_call("set_timer", CYCLES_SET_TIMER)?;
+    let timer = ctx!(caller).env_mut().sleep(Duration::from_millis(delay_ms));
+    ctx!(caller).queue_task(
+        promise_id,
+        format!("Timer ({}ms)", delay_ms),
+        Box::pin(async move {
+            timer.await;
+            Ok(Vec::new())
+        })
+    );
+    Ok(())
+}
+
 // Reads a UTF-16LE encoded string from the guest memory at the given pointer.
 // TODO: What about error handling? Host function should be able to return Result as well?
 fn read_utf16_string(caller: &wasmi::Caller<Ctx>, str_ptr: i32, max_len: usize) -> Result<String, Error> {
@@ -248,6 +512,7 @@ fn write_utf16_string(mut caller: Caller<Ctx>, str: &String, buffer_ptr: i32) ->
 /// The guest is expected to have allocated a buffer of the same size.
 fn copy_shared_buffer(mut caller: Caller<Ctx>, buffer_ptr: i32) -> Result<(), Error> {
     let shared_buffer = ctx!(caller).commit_context().shared_buffer.clone();
+    ctx!(caller).charge_host_call("copy_shared_buffer", COST_COPY_BUFFER.cycles(shared_buffer.len()))?;
     get_memory(&caller).write(caller, buffer_ptr as usize, &shared_buffer)?;
     Ok(())
 }
@@ -255,25 +520,28 @@ fn copy_shared_buffer(mut caller: Caller<Ctx>, buffer_ptr: i32) -> Result<(), Er
 /// Reads a buffer from the guest memory at the given pointer.
 /// The length of the buffer is presumed to be in the first 4 bytes before the pointer.
 fn read_buffer(caller: &Caller<Ctx>, ptr: i32, max_len: usize) -> Result<Vec<u8>, Error> {
-    let memory = get_memory(caller);
-
-    // Read buffer length stored at (ptr - 4)
-    let ptr = ptr as u32 as usize;
-    let mut buf_len = [0u8; 4];
-    memory.read(caller, ptr - 4, &mut buf_len)
-        .map_err(|e| Error::new(format!("Failed reading buffer length: {}", e)))?;
-    let buf_len = u32::from_le_bytes(buf_len) as usize;
+    let buf_len = read_buffer_len(caller, ptr)?;
     if buf_len > max_len {
         return Err(Error::new(format!("Buffer length {} exceeds maximum allowed {}", buf_len, max_len)));
     }
 
     // Read the bytes into the buffer
     let mut bytes = vec![0u8; buf_len];
-    memory.read(caller, ptr, &mut bytes)
+    get_memory(caller).read(caller, ptr as u32 as usize, &mut bytes)
         .map_err(|e| Error::new(format!("Failed reading buffer: {}", e)))?;
     Ok(bytes)
 }
 
+/// Reads the length of the buffer at the given pointer, which is stored in
+/// the 4 bytes before the pointer.
+fn read_buffer_len(caller: &Caller<Ctx>, ptr: i32) -> Result<usize, Error> {
+    let ptr = ptr as u32 as usize;
+    let mut buf_len = [0u8; 4];
+    get_memory(caller).read(caller, ptr.wrapping_sub(4), &mut buf_len)
+        .map_err(|e| Error::new(format!("Failed reading buffer length: {}", e)))?;
+    Ok(u32::from_le_bytes(buf_len) as usize)
+}
+
 fn get_memory(caller: &Caller<Ctx>) -> Memory {
     caller
         .get_export("memory")
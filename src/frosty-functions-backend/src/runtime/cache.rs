@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::CandidType;
+use ic_cdk::api::instruction_counter;
+use wasmi::{Engine, Module};
+
+use crate::repository::FunctionId;
+use crate::runtime::FailureReason;
+
+/// Maximum number of compiled modules kept in the cache.
+const MAX_ENTRIES: usize = 64;
+
+/// Maximum combined size of the WASM binaries of all cached modules. Compiled
+/// modules are larger than their binaries, but grow roughly proportionally.
+const MAX_CACHED_BYTES: usize = 32 * 1024 * 1024;
+
+thread_local! {
+    // All modules are compiled with the same engine so that they can be cached.
+    static ENGINE: Engine = {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    };
+
+    // Compiled modules of recently executed functions. The cache lives on the heap
+    // and is cleared on upgrades. Changes made in query calls are discarded.
+    static CACHE: RefCell<ModuleCache> = RefCell::new(ModuleCache::default());
+}
+
+#[derive(Default)]
+struct ModuleCache {
+    entries: HashMap<FunctionId, CacheEntry>,
+    // Incremented on every access to determine the least recently used entry.
+    clock: u64,
+    cached_bytes: usize,
+    metrics: ModuleCacheMetrics,
+}
+
+struct CacheEntry {
+    module: Module,
+    last_used: u64,
+    binary_size: usize,
+    // Instructions it took to compile the module.
+    compile_instructions: u64,
+}
+
+/// Counters of the module cache since the last upgrade.
+#[derive(Clone, Debug, Default, CandidType)]
+pub struct ModuleCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Instructions saved by not compiling modules on cache hits.
+    pub instructions_saved: u64,
+    /// Number of modules currently cached.
+    pub entries: u64,
+}
+
+/// Compiles the given WASM binary without caching it.
+pub fn compile_module(wasm: &[u8]) -> Result<Module, FailureReason> {
+    ENGINE.with(|engine| Module::new(engine, wasm))
+        .map_err(|e| FailureReason::InvalidModule(format!("Failed to load WASM module: {}", e)))
+}
+
+/// Returns the compiled module of the given function, compiling and caching
+/// it if necessary.
+pub fn cached_module(id: &FunctionId, wasm: &[u8]) -> Result<Module, FailureReason> {
+    if let Some(module) = CACHE.with_borrow_mut(|cache| cache.get(id)) {
+        return Ok(module);
+    }
+    let started_at = instruction_counter();
+    let module = compile_module(wasm)?;
+    let compile_instructions = instruction_counter() - started_at;
+    CACHE.with_borrow_mut(|cache| cache.insert(id.clone(), module.clone(), wasm.len(), compile_instructions));
+    Ok(module)
+}
+
+pub fn metrics() -> ModuleCacheMetrics {
+    CACHE.with_borrow(|cache| ModuleCacheMetrics {
+        entries: cache.entries.len() as u64,
+        ..cache.metrics.clone()
+    })
+}
+
+impl ModuleCache {
+    fn get(&mut self, id: &FunctionId) -> Option<Module> {
+        self.clock += 1;
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.metrics.hits += 1;
+                self.metrics.instructions_saved += entry.compile_instructions;
+                Some(entry.module.clone())
+            }
+            None => {
+                self.metrics.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, id: FunctionId, module: Module, binary_size: usize, compile_instructions: u64) {
+        if binary_size > MAX_CACHED_BYTES {
+            return;
+        }
+        while self.entries.len() >= MAX_ENTRIES || self.cached_bytes + binary_size > MAX_CACHED_BYTES {
+            self.evict_least_recently_used();
+        }
+        self.clock += 1;
+        self.cached_bytes += binary_size;
+        let entry = CacheEntry { module, last_used: self.clock, binary_size, compile_instructions };
+        if let Some(previous) = self.entries.insert(id, entry) {
+            self.cached_bytes -= previous.binary_size;
+        }
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(id, _)| id.clone());
+        if let Some(entry) = oldest.and_then(|id| self.entries.remove(&id)) {
+            self.cached_bytes -= entry.binary_size;
+            self.metrics.evictions += 1;
+        }
+    }
+}
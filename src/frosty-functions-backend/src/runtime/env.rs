@@ -1,4 +1,8 @@
-use crate::runtime::{Commit, JobRequest};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::runtime::{Commit, FailureReason, JobRequest};
 
 /// Trait to be implemented by consumers of the runtime module to provide
 /// any functionlity that requires access to the outside world or information.
@@ -10,15 +14,29 @@ pub trait RuntimeEnvironment {
     fn job_request(&self) -> JobRequest;
 
     /// Charges the given fee in the calling currency using the gas balance.
-    /// Returns an Error if insufficient funds are available.
-    fn charge_fee(&mut self, fee: u64) -> Result<(), String>;
+    /// Returns OutOfGas if insufficient funds are available.
+    fn charge_fee(&mut self, fee: u64) -> Result<(), FailureReason>;
 
-    /// Charges the given fee in the calling currency using the gas balance.
-    /// Gas fees will be accounted separately from executions fees as they
-    /// are charged by the calling chain rather than by ICP / Frosty.
-    /// Returns an Error if insufficient funds are available.
-    // TODO: Remove this, no longer allowing native transactions on the main account.
-    fn charge_gas(&mut self, gas: u64) -> Result<(), String>;
+    /// Conversion rate between cycles and the calling currency (wei) used to
+    /// charge for cycles consumed by the execution.
+    fn wei_per_cycle(&self) -> u64;
+
+    /// Flat fee in the calling currency charged per call of the given host
+    /// function, on top of the cycles consumed.
+    fn host_call_surcharge(&self, name: &str) -> u64;
+
+    /// Reports a fee charged for a host function call. The fee has already been
+    /// charged through charge_fee, so this is purely informational.
+    fn record_host_call_fee(&mut self, _name: &str, _fee: u64) {}
+
+    /// Current time (Unix nanoseconds).
+    fn time(&self) -> u64;
+
+    /// Returns a Future that resolves after the given delay.
+    fn sleep(&mut self, delay: Duration) -> Pin<Box<dyn Future<Output = ()>>>;
+
+    /// Stores the result of the job, replacing any previous result.
+    fn set_result(&mut self, result: Vec<u8>);
 
     /// Submits a commit to be stored persistently.
     fn commit(&mut self, commit: Commit);
@@ -1,13 +1,11 @@
+use std::fmt;
 use std::ops::Sub;
 
 use candid::{CandidType, Nat};
-use evm_rpc_types::{Hex32, Nat256};
+use evm_rpc_types::{Hex20, Hex32, Nat256};
 use serde::{Deserialize, Serialize};
 
-use crate::{chain::{Address, Chain}, repository::FunctionId};
-
-// Base fee per execution in wei.
-const BASE_FEE_WEI: u64 = 1_000_000_000_000;  // 1,000 gwei
+use crate::{chain::{Address, Chain}, delivery::Delivery, refund::Refund, repository::FunctionId};
 
 /// Request for executing a function. Currently these are created from EVM logs,
 /// but in the future they could also come from other sources such as other chains,
@@ -34,6 +32,8 @@ pub struct JobRequest {
     pub data: Vec<u8>,
     /// Gas payment forwarded with the call in the native currency of the calling chain.
     pub gas_payment: Nat256,
+    /// Contract that should receive the result once the job finished.
+    pub callback: Option<Hex20>,
 }
 
 /// Job with metadata and execution state.
@@ -54,18 +54,31 @@ pub struct Job {
     pub execution_fees: u64,
     // Gas used for transactions on the calling chain (e.g. depositGas).
     pub gas_fees: u64,
+    // Conversion rate between cycles and wei used for execution fees of this job,
+    // fixed at creation so that fees can be reproduced.
+    pub wei_per_cycle: u64,
+    // Refund of unused gas, set once the job reached a terminal status.
+    pub refund: Option<Refund>,
+    // Result set by the function through set_result.
+    pub result: Option<Vec<u8>>,
+    // Delivery of the result to the callback contract, if one was requested.
+    pub delivery: Option<Delivery>,
 }
 
 impl Job {
-    pub fn new(request: JobRequest) -> Self {
+    pub fn new(request: JobRequest, base_fee: u64, wei_per_cycle: u64) -> Self {
         Self {
             request,
             status: JobStatus::Pending,
             created_at: ic_cdk::api::time(),
             commit_ids: Vec::new(),
-            base_fee: BASE_FEE_WEI,  // TODO: Support different chains.
+            base_fee,
             execution_fees: 0,
             gas_fees: 0,
+            wei_per_cycle,
+            refund: None,
+            result: None,
+            delivery: None,
         }
     }
 
@@ -74,12 +87,24 @@ impl Job {
     }
 
     pub fn remaining_gas(&self) -> Nat {
-        return self.request.gas_payment.as_ref().clone().sub(self.total_cost());
+        // Jobs rejected for paying less than the minimum payment might not even
+        // cover the base fee.
+        let payment = self.request.gas_payment.as_ref().clone();
+        let total_cost = Nat::from(self.total_cost());
+        if payment < total_cost {
+            return Nat::from(0u64);
+        }
+        payment.sub(total_cost)
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, CandidType)]
 pub enum JobStatus {
+    /// Job was found on-chain, but its block is not finalized yet.
+    AwaitingFinality,
+    /// The block the job was found in got orphaned by a re-org. The job
+    /// won't be executed.
+    Reorged,
     /// Job was added to the queue, but not yet processed.
     Pending,
     /// Job is currently being executed.
@@ -89,7 +114,92 @@ pub enum JobStatus {
     /// Job completed without errors.
     Completed,
     /// Job execution failed.
-    Failed(String)  // Change to proper error type.
+    Failed(FailureReason),
+}
+
+impl JobStatus {
+    pub fn kind(&self) -> JobStatusKind {
+        match self {
+            JobStatus::AwaitingFinality => JobStatusKind::AwaitingFinality,
+            JobStatus::Reorged => JobStatusKind::Reorged,
+            JobStatus::Pending => JobStatusKind::Pending,
+            JobStatus::Executing => JobStatusKind::Executing,
+            JobStatus::Waiting => JobStatusKind::Waiting,
+            JobStatus::Completed => JobStatusKind::Completed,
+            JobStatus::Failed(_) => JobStatusKind::Failed,
+        }
+    }
+}
+
+/// Status of a job without details, used for filtering jobs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum JobStatusKind {
+    AwaitingFinality,
+    Reorged,
+    Pending,
+    Executing,
+    Waiting,
+    Completed,
+    Failed,
+}
+
+/// Filter for listing jobs. All criteria that are set need to match.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct JobFilter {
+    pub function_hash: Option<FunctionId>,
+    /// Address of the caller, on any chain.
+    pub caller: Option<Address>,
+    pub status: Option<JobStatusKind>,
+}
+
+impl JobFilter {
+    pub fn matches(&self, job: &Job) -> bool {
+        self.function_hash.as_ref().is_none_or(|hash| hash == &job.request.function_hash)
+            && self.caller.as_ref().is_none_or(|caller| caller == &job.request.caller)
+            && self.status.is_none_or(|status| status == job.status.kind())
+    }
+}
+
+/// Page of jobs returned by `list_jobs`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct JobPage {
+    pub jobs: Vec<Job>,
+    /// Cursor for the next page. None if there are no more jobs.
+    pub next_cursor: Option<u64>,
+}
+
+/// Reason for a failed job. Distinguishes between errors in user code and
+/// faults of the platform.
+#[derive(Clone, Debug, Deserialize, Serialize, CandidType)]
+pub enum FailureReason {
+    /// No function with the given ID.
+    FunctionNotFound,
+    /// The gas payment doesn't cover the minimum payment of the chain.
+    InsufficientPayment { min_payment: u64 },
+    /// WASM module is invalid or malformed.
+    InvalidModule(String),
+    /// Ran out of gas during execution.
+    OutOfGas(String),
+    /// The function tried to use more than `limit` bytes of linear memory.
+    OutOfMemory { limit: u64 },
+    /// Uncaught exception or trap in user code.
+    UncaughtException(String),
+    /// Something that should not happen.
+    SystemError(String),
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::FunctionNotFound => write!(f, "No WASM binary found for function"),
+            FailureReason::InsufficientPayment { min_payment } => write!(f, "Gas payment below minimum of {}", min_payment),
+            FailureReason::InvalidModule(message) => write!(f, "Invalid WASM module: {}", message),
+            FailureReason::OutOfGas(message) => write!(f, "Out of gas: {}", message),
+            FailureReason::OutOfMemory { limit } => write!(f, "Out of memory: Memory is limited to {} bytes", limit),
+            FailureReason::UncaughtException(message) => write!(f, "{}", message),
+            FailureReason::SystemError(message) => write!(f, "System error: {}", message),
+        }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -115,14 +225,3 @@ pub enum LogType {
     System,
     Default,
 }
-
-/*
-type FailureReason = variant {
-  FunctionNotFound;      // No function with the given ID
-  InvalidModule;         // Wasm module is invalid or malformed
-  OutOfGas;              // Ran out of gas during execution
-  UncaughtException;     // Uncaught exception in user code
-  SystemError;           // Something that should not happen.
-};
-
-*/
@@ -1,8 +1,10 @@
 mod api;
+mod cache;
 mod env;
 mod job;
 mod runtime;
 
+pub use cache::{ModuleCacheMetrics, cached_module, compile_module, metrics as module_cache_metrics};
 pub use env::{RuntimeEnvironment};
-pub use job::{Commit, Job, JobRequest, JobStatus, LogEntry, LogType};
-pub use runtime::{Execution};
+pub use job::{Commit, FailureReason, Job, JobFilter, JobPage, JobRequest, JobStatus, JobStatusKind, LogEntry, LogType};
+pub use runtime::{DEFAULT_MEMORY_LIMIT, Execution, MAX_MEMORY_LIMIT, validate_module};
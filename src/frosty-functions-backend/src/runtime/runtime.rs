@@ -5,124 +5,285 @@ use std::pin::Pin;
 use std::rc::Rc;
 
 use ic_cdk::api::instruction_counter;
-use wasmi::{Error, Linker, TypedResumableCall, WasmParams};
-use wasmi::{Engine, Module, TypedFunc};
+use wasmi::{CallHook, Error, Linker, TypedResumableCall, TypedResumableCallOutOfFuel, WasmParams};
+use wasmi::{ExternType, Module, ResourceLimiter, StoreLimits, StoreLimitsBuilder, TypedFunc};
+use wasmi::errors::LimiterError;
 
 use crate::runtime::api::{register_constants, register_host_functions};
-use crate::runtime::{Commit, LogEntry, LogType, RuntimeEnvironment};
+use crate::runtime::cache::compile_module;
+use crate::runtime::{Commit, FailureReason, LogEntry, LogType, RuntimeEnvironment};
 
-// Maximum number of host (IC) instructions per job.
-// TODO: Increase this except for simulations.
-// TODO: Remove the limit altogether by yielding control back to IC.
+// Maximum number of host (IC) instructions for executions that can't yield
+// control back to the IC, i.e. simulations.
 const HOST_INSTRUCTION_LIMIT: u64 = 1_000_000_000;
 
-// Number of (guest) WASM instructions before we check gas/fuel again.
-// TODO: Calling back into a host function can be very expensive, so we
-// may still hit the host limit before checking. Ideally we would check within
-// each host function call as well. We should be able to do that with call_hook
-// and then call set_fuel from there.
+// Number of host (IC) instructions per message after which executions yield
+// control back to the IC and continue in a fresh message. Needs to leave enough
+// headroom below the IC's per-message limit to finish the current fuel batch.
+const SLICE_INSTRUCTION_LIMIT: u64 = 20_000_000_000;
+
+// Number of host (IC) instructions per message after which host calls fail.
+// Executions only yield between fuel batches, so expensive host calls in a
+// tight loop could otherwise exceed the IC's per-message limit of 40B
+// instructions and trap the whole message.
+const MESSAGE_INSTRUCTION_LIMIT: u64 = 35_000_000_000;
+
+// Number of (guest) WASM instructions before we check gas/fuel again. Host
+// function calls are metered separately through a call hook, see meter_host_call.
 // TODO: If we check fuel in each host function, we should be able to replace
 // this constant with just calculating the fuel instead (e.g. remaining instructions / 10).
 const FUEL_PER_BATCH: u64 = 10_000_000;
 
-// Conversion rate between cycles and native currency (wei).
-// TODO: Calculate dynamically based on XDR:ETH price.
-// Note: Add the time of writing, 1 cycle costs approximatey 430 wei,
-// but we need to leave some margin for price fluctuations.
-const WEI_PER_CYCLE: u64 = 1000;
+/// Linear memory limit (bytes) for functions that didn't request a specific limit.
+pub const DEFAULT_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Maximum linear memory limit (bytes) that functions can request.
+pub const MAX_MEMORY_LIMIT: u64 = 1024 * 1024 * 1024;
+
+/// Maximum number of elements per table.
+const TABLE_ELEMENTS_LIMIT: usize = 10_000;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Cycles charged for each page of linear memory the guest allocates. Heap
+// memory is not free on the IC, and a job may keep it for a long time.
+// TODO: Calculate exact value.
+const CYCLES_PER_MEMORY_PAGE: u64 = 1_000_000;
+
+/// Namespaces that WASM modules may import from.
+const IMPORT_NAMESPACES: [&str; 2] = ["❄️", "env"];
+
+/// Functions that WASM modules need to export.
+const REQUIRED_EXPORTS: [&str; 3] = ["main", "__frosty_resolve", "__frosty_reject"];
+
+/// Parses the given WASM module and checks that it only imports from the
+/// supported namespaces and exports all functions required by the runtime.
+/// Returns the names of all imports.
+pub fn validate_module(wasm: &[u8]) -> Result<Vec<String>, String> {
+    let module = compile_module(wasm).map_err(|e| e.to_string())?;
+    for name in REQUIRED_EXPORTS {
+        let is_function = module.exports()
+            .any(|export| export.name() == name && matches!(export.ty(), ExternType::Func(_)));
+        if !is_function {
+            return Err(format!("{}() function missing", name));
+        }
+    }
+    module.imports()
+        .map(|import| {
+            if !IMPORT_NAMESPACES.contains(&import.module()) {
+                return Err(format!("Unsupported import {}.{}: Only the {} namespaces are available",
+                    import.module(), import.name(), IMPORT_NAMESPACES.join(" and ")));
+            }
+            Ok(format!("{}.{}", import.module(), import.name()))
+        })
+        .collect()
+}
+
+/// Data owned by the wasmi store.
+pub struct StoreData {
+    pub ctx: Rc<RefCell<ExecutionContext>>,
+    limits: StoreLimits,
+    // Linear memory limit (bytes).
+    memory_limit: u64,
+    // Whether the last attempt to grow memory was denied because it would have
+    // exceeded memory_limit.
+    memory_exhausted: bool,
+}
+
+/// Delegates to StoreLimits, but keeps track of whether growing memory was
+/// denied by the memory limit. Failed growth returns -1 to the guest, which
+/// may retry with a smaller size, so only the last attempt matters.
+impl ResourceLimiter for StoreData {
+    fn memory_growing(&mut self, current: usize, desired: usize, maximum: Option<usize>) -> Result<bool, LimiterError> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        self.memory_exhausted = !allowed && desired as u64 > self.memory_limit;
+        Ok(allowed)
+    }
+
+    fn table_growing(&mut self, current: usize, desired: usize, maximum: Option<usize>) -> Result<bool, LimiterError> {
+        self.limits.table_growing(current, desired, maximum)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
 
 /// Runtime state for a job execution. All methods are synchronous and the caller is expected
 /// to handle scheudling of async operations.
 pub struct Execution {
-    store: wasmi::Store<Rc<RefCell<ExecutionContext>>>,
+    store: wasmi::Store<StoreData>,
     instance: wasmi::Instance,
     fn_main: TypedFunc<(), ()>,
     fn_resolve: TypedFunc<(i32, i32), ()>,
     fn_reject: TypedFunc<(i32, i32), ()>,
+    // Call that yielded because the instruction limit for the current message was reached.
+    parked: Option<ParkedCall>,
+    // Linear memory limit (bytes).
+    memory_limit: u64,
+    // Number of memory pages that have been charged for so far.
+    charged_memory_pages: u64,
+}
+
+/// A call that ran out of instructions for the current message and needs to be
+/// resumed in a fresh message.
+struct ParkedCall {
+    call: TypedResumableCallOutOfFuel<()>,
+    title: String,
+    shared_buffer: Vec<u8>,
 }
 
 impl Execution {
-    /// Instantiates the WASM module and runs its main() function.
-    pub fn run_main(wasm: &[u8], env: impl RuntimeEnvironment + 'static) -> Result<Self, String> {
+    /// Instantiates the compiled WASM module and runs its main() function. The
+    /// linear memory of the module is limited to `memory_limit` bytes.
+    pub fn run_main(module: &Module, memory_limit: u64, env: impl RuntimeEnvironment + 'static) -> Result<Self, FailureReason> {
         let mut context = ExecutionContext {
             env: Box::new(env),
             commit_context: None,
             queued_futures: VecDeque::new(),
+            failure: None,
         };
-        context.commit_begin();  // Can't use with_commit here because ownership will move.
+        context.commit_begin("main()".to_string());  // Can't use with_commit here because ownership will move.
 
         let context = Rc::new(RefCell::new(context));
-        let mut execution = Self::init(wasm, context)?;
+        let mut execution = Self::init(module, memory_limit, context)?;
         execution.ctx().borrow_mut().log(format!("WASM module instantiated"));
-        execution.call(execution.fn_main, ())?;
-        execution.ctx().borrow_mut().commit_end("main()".to_string());
+        let result = execution.call(execution.fn_main, ());
+        execution.end_commit("main()".to_string(), result)?;
         // TODO: Return ExecutionResult of main as well.
         Ok(execution)
     }
 
+    /// Whether a call yielded and needs to be resumed in a fresh message
+    /// before any callbacks can be executed.
+    pub fn is_parked(&self) -> bool {
+        self.parked.is_some()
+    }
+
+    /// Resumes the parked call. Should be invoked in a fresh message, as the
+    /// instruction counter is checked against the per-message limit.
+    pub fn resume(&mut self) -> Result<(), FailureReason> {
+        let parked = self.parked.take()
+            .ok_or_else(|| FailureReason::SystemError("No parked call to resume".to_string()))?;
+        let title = format!("Resumed {}", parked.title);
+        self.with_commit(title, |exec| {
+            exec.ctx().borrow_mut().commit_context().shared_buffer = parked.shared_buffer;
+            exec.ctx().borrow_mut().log("Resuming execution in a new message".to_string());
+            let result = parked.call.resume(&mut exec.store);
+            exec.drive(result, parked.title)
+        })
+    }
+
     /// Instantiates and starts the WASM module.
-    fn init(wasm: &[u8], context: Rc<RefCell<ExecutionContext>>) -> Result<Self, String> {
-        // TODO: Cache the engine instead of recreating it for each execution?
-        let mut config = wasmi::Config::default();
-        config.consume_fuel(true);
-        let engine = Engine::new(&config);
-        let module = Module::new(&engine, &wasm[..]).map_err(|e| format!("Failed to load WASM module: {}", e))?;
-        
-        let mut store = wasmi::Store::new(module.engine(), context);
+    fn init(module: &Module, memory_limit: u64, context: Rc<RefCell<ExecutionContext>>) -> Result<Self, FailureReason> {
+        // Growing memory beyond the limit returns -1 rather than trapping, as the
+        // AssemblyScript allocator first tries to double the memory and only
+        // falls back to the pages it needs if that fails.
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(memory_limit as usize)
+            .table_elements(TABLE_ELEMENTS_LIMIT)
+            .trap_on_grow_failure(false)
+            .build();
+        let data = StoreData { ctx: context, limits, memory_limit, memory_exhausted: false };
+        let mut store = wasmi::Store::new(module.engine(), data);
+        store.limiter(|data| data);
         // TODO: Set instruction limit (fuel) based on available gas.
-        store.set_fuel(FUEL_PER_BATCH).map_err(|e| format!("Failed to set fuel: {}", e))?;
+        store.set_fuel(FUEL_PER_BATCH).map_err(|e| FailureReason::SystemError(format!("Failed to set fuel: {}", e)))?;
+        store.call_hook(|data, hook| data.ctx.borrow_mut().meter_host_call(hook));
 
         // Create linker with host functions and constants.
         let mut linker = Linker::new(module.engine());
         register_constants(&mut linker, &mut store)
-            .map_err(|e| format!("Failed to register constants: {}", e))?;
+            .map_err(|e| FailureReason::SystemError(format!("Failed to register constants: {}", e)))?;
         register_host_functions(&mut linker, &mut store)
-            .map_err(|e| format!("Failed to register host functions: {}", e))?;
+            .map_err(|e| FailureReason::SystemError(format!("Failed to register host functions: {}", e)))?;
 
         // Initialize and start the module instance.
-        let instance = linker.instantiate_and_start(&mut store, &module)
-            .map_err(|e| format!("Failed to instantiate WASM module: {}", e))?;
+        let instance = linker.instantiate_and_start(&mut store, module)
+            .map_err(|e| FailureReason::InvalidModule(format!("Failed to instantiate WASM module: {}", e)))?;
+        let missing = |name: &str, e: Error| FailureReason::InvalidModule(format!("{}() function missing: {}", name, e));
         Ok(Self {
-            fn_main: instance.get_typed_func::<(), ()>(&store, "main").map_err(|e| format!("main() function missing: {}", e))?,
-            fn_resolve: instance.get_typed_func::<(i32, i32), ()>(&store, "__frosty_resolve").map_err(|e| format!("__frosty_resolve() function missing: {}", e))?,
-            fn_reject: instance.get_typed_func::<(i32, i32), ()>(&store, "__frosty_reject").map_err(|e| format!("__frosty_reject() function missing: {}", e))?,
+            fn_main: instance.get_typed_func::<(), ()>(&store, "main").map_err(|e| missing("main", e))?,
+            fn_resolve: instance.get_typed_func::<(i32, i32), ()>(&store, "__frosty_resolve").map_err(|e| missing("__frosty_resolve", e))?,
+            fn_reject: instance.get_typed_func::<(i32, i32), ()>(&store, "__frosty_reject").map_err(|e| missing("__frosty_reject", e))?,
             store,
             instance,
+            parked: None,
+            memory_limit,
+            charged_memory_pages: 0,
         })
     }
 
     /// Calls a function of the WASM module, handling fuel consumption and errors.
-    fn call<Params: WasmParams>(&mut self, function: TypedFunc<Params, ()>, params: Params) -> Result<(), String> {
-        let mut result = function.call_resumable(&mut self.store, params);
+    fn call<Params: WasmParams>(&mut self, function: TypedFunc<Params, ()>, params: Params) -> Result<(), FailureReason> {
+        let title = self.ctx().borrow_mut().commit_context().title.clone();
+        let result = function.call_resumable(&mut self.store, params);
+        self.drive(result, title)
+    }
+
+    /// Drives a resumable call until it finishes, traps or needs to yield. If
+    /// the instruction limit of the current message is reached, the call is
+    /// parked and Ok is returned.
+    fn drive(&mut self, mut result: Result<TypedResumableCall<()>, Error>, title: String) -> Result<(), FailureReason> {
         loop {
             match result {
                 Ok(TypedResumableCall::Finished(_)) => {
                     return Ok(());
                 }
                 Ok(TypedResumableCall::HostTrap(trap)) => {
-                    return Err(trap.host_error().to_string());
+                    // Host functions record typed failures (e.g. out of gas) in the
+                    // context. Anything else is caused by invalid use of the API.
+                    return Err(self.ctx().borrow_mut().failure.take()
+                        .unwrap_or_else(|| self.trap_reason(trap.host_error().to_string())));
                 }
                 Ok(TypedResumableCall::OutOfFuel(resumable)) => {
                     // TODO: Also re-check available gas here.
                     let instructions = instruction_counter();
-                    // ic_cdk::api::debug_print(format!("Re-fueling WASM execution. Instruction counter: {}", instructions));
-                    if instructions >= HOST_INSTRUCTION_LIMIT {
-                        return Err(format!("Instruction limit reached: The number of host instructions is currently limited to {:#}", HOST_INSTRUCTION_LIMIT));
+                    let can_yield = !self.ctx().borrow().env().is_simulation();
+                    if can_yield && instructions >= SLICE_INSTRUCTION_LIMIT {
+                        // Yield back to the IC. The caller resumes in a fresh message.
+                        self.store.set_fuel(FUEL_PER_BATCH).map_err(|e| FailureReason::SystemError(format!("Failed to refuel: {}", e)))?;
+                        let shared_buffer = self.ctx().borrow_mut().commit_context().shared_buffer.clone();
+                        self.parked = Some(ParkedCall { call: resumable, title, shared_buffer });
+                        return Ok(());
+                    } else if !can_yield && instructions >= HOST_INSTRUCTION_LIMIT {
+                        return Err(FailureReason::OutOfGas(format!("Instruction limit reached: The number of host instructions is currently limited to {:#}", HOST_INSTRUCTION_LIMIT)));
                     } else {
                         // Keep executing after refueling.
-                        self.store.set_fuel(FUEL_PER_BATCH).map_err(|e| format!("Failed to refuel: {}", e))?;
+                        self.store.set_fuel(FUEL_PER_BATCH).map_err(|e| FailureReason::SystemError(format!("Failed to refuel: {}", e)))?;
                         result = resumable.resume(&mut self.store);
                     }
                 }
                 Err(e) => {
-                    return Err(format!("WASM execution failed: {}", e));
+                    // Errors raised by the call hook, e.g. running out of gas.
+                    if let Some(failure) = self.ctx().borrow_mut().failure.take() {
+                        return Err(failure);
+                    }
+                    return Err(self.trap_reason(format!("WASM execution failed: {}", e)));
                 }
             }
         }
     }
 
+    /// Reason for a trap in user code. Allocators usually trap or abort when
+    /// they can't grow memory, which is reported as running out of memory.
+    fn trap_reason(&self, message: String) -> FailureReason {
+        if self.store.data().memory_exhausted {
+            return FailureReason::OutOfMemory { limit: self.memory_limit };
+        }
+        FailureReason::UncaughtException(message)
+    }
+
     /// Executes the callback for the given AsyncResult.
-    pub fn callback(&mut self, result: AsyncResult) -> Result<(), String> {
+    pub fn callback(&mut self, result: AsyncResult) -> Result<(), FailureReason> {
         match result.result {
             Ok(data) => {
                 let title = format!("Resolving Promise #{}: {}", result.promise_id, result.description);
@@ -154,18 +315,49 @@ impl Execution {
 
     // TODO: Return reference instead. Also have ctx_mut() for mutable access.
     fn ctx(&mut self) -> Rc<RefCell<ExecutionContext>> {
-        self.store.data().clone()
+        self.store.data().ctx.clone()
+    }
+
+    /// Current size of the linear memory in bytes.
+    fn memory_size(&self) -> u64 {
+        self.instance.get_memory(&self.store, "memory")
+            .map(|memory| memory.data_size(&self.store) as u64)
+            .unwrap_or(0)
+    }
+
+    /// Charges for memory pages allocated since the last charge.
+    fn charge_memory_growth(&mut self) -> Result<(), Error> {
+        let pages = self.memory_size().div_ceil(WASM_PAGE_SIZE);
+        if pages <= self.charged_memory_pages {
+            return Ok(());
+        }
+        let new_pages = pages - self.charged_memory_pages;
+        self.charged_memory_pages = pages;
+        self.ctx().borrow_mut().charge_cycles(new_pages * CYCLES_PER_MEMORY_PAGE)
     }
 
     /// Executes the given function within a CommitContext. Many operations such as logging
     /// or scheduling async tasks require a CommitContext to be present. After execution,
     /// `commit()` is called on the RuntimeEnvionment to persist the commit.
-    fn with_commit<R>(&mut self, title: String, f: impl FnOnce(&mut Self) -> R) -> R {
-        self.ctx().borrow_mut().commit_begin();
+    fn with_commit(&mut self, title: String, f: impl FnOnce(&mut Self) -> Result<(), FailureReason>) -> Result<(), FailureReason> {
+        self.ctx().borrow_mut().commit_begin(title.clone());
         let result = f(self);
-        self.ctx().borrow_mut().commit_end(title);
-        result
-    }    
+        self.end_commit(title, result)
+    }
+
+    /// Ends the current commit, charging for the instructions and memory used.
+    /// Returns the result of the execution or an error if the instructions or
+    /// memory couldn't be paid for.
+    fn end_commit(&mut self, title: String, result: Result<(), FailureReason>) -> Result<(), FailureReason> {
+        let title = if self.is_parked() { format!("{} (yielded)", title) } else { title };
+        let memory_charged = self.charge_memory_growth();
+        let charged = self.ctx().borrow_mut().commit_end(title);
+        result?;
+        memory_charged.and(charged).map_err(|e| {
+            self.ctx().borrow_mut().failure.take()
+                .unwrap_or_else(|| FailureReason::OutOfGas(e.to_string()))
+        })
+    }
 }
 
 /// Runtime context available to host functions during execution.
@@ -175,6 +367,8 @@ pub struct ExecutionContext {
     commit_context: Option<CommitContext>,
     // Queue for Futures that should be spawned.
     queued_futures: VecDeque<AsyncFuture>,
+    // Typed reason for the error returned by the last failed host call, if any.
+    failure: Option<FailureReason>,
 }
 
 impl ExecutionContext {
@@ -199,16 +393,72 @@ impl ExecutionContext {
     // TODO: Change Error type to something better.
     pub fn charge_fee(&mut self, fee: u64) -> Result<(), Error> {
         self.env.as_mut().charge_fee(fee)
-            .map_err(|e| Error::new(e))?;
+            .map_err(|reason| self.fail(reason))?;
         // Tracking of fees on the commit level is purely for informational
         // purposes to make debugging easier for developers.
         self.commit_context().fees += fee;
         Ok(())
     }
 
-    /// Wrapper around charge_fee that converts cycles to native currency.
+    /// Records the typed reason for a failure and returns an Error that can be
+    /// propagated through wasmi. The execution picks up the reason once wasmi
+    /// returns the error.
+    pub fn fail(&mut self, reason: FailureReason) -> Error {
+        let error = Error::new(reason.to_string());
+        self.failure = Some(reason);
+        error
+    }
+
+    /// Called before and after each host function call. Charges for the guest
+    /// instructions executed since the last check, so that running out of gas
+    /// is detected before doing any (potentially expensive) work in the host
+    /// function. The host function itself is priced through charge_host_call,
+    /// so its instructions are skipped when returning.
+    fn meter_host_call(&mut self, hook: CallHook) -> Result<(), Error> {
+        let Some(commit_context) = self.commit_context.as_mut() else {
+            return Ok(());
+        };
+        let counter = instruction_counter();
+        let instructions = counter - commit_context.charged_instruction_counter;
+        commit_context.charged_instruction_counter = counter;
+        match hook {
+            CallHook::CallingHost => {
+                if self.env.is_simulation() && counter >= HOST_INSTRUCTION_LIMIT {
+                    return Err(self.fail(FailureReason::OutOfGas(format!("Instruction limit reached: The number of host instructions is currently limited to {:#}", HOST_INSTRUCTION_LIMIT))));
+                }
+                if counter >= MESSAGE_INSTRUCTION_LIMIT {
+                    return Err(self.fail(FailureReason::OutOfGas(format!("Instruction limit reached: The number of host instructions per message is limited to {:#}", MESSAGE_INSTRUCTION_LIMIT))));
+                }
+                self.charge_cycles(instructions)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wrapper around charge_fee that converts cycles to native currency,
+    /// using the rate of the job's chain.
     pub fn charge_cycles(&mut self, cycles: u64) -> Result<(), Error> {
-        self.charge_fee(cycles * WEI_PER_CYCLE)
+        let fee = self.cycles_to_fee(cycles, 0)?;
+        self.charge_fee(fee)
+    }
+
+    /// Charges the cycles consumed by the given host function plus its surcharge
+    /// and reports the fee to the environment, which allows for a per host
+    /// function breakdown.
+    pub fn charge_host_call(&mut self, name: &str, cycles: u64) -> Result<(), Error> {
+        let fee = self.cycles_to_fee(cycles, self.env.host_call_surcharge(name))?;
+        self.charge_fee(fee)?;
+        self.env.record_host_call_fee(name, fee);
+        Ok(())
+    }
+
+    /// Converts cycles to native currency and adds the surcharge. Rates come
+    /// from an exchange rate oracle or the configuration, so fees that don't fit
+    /// into u64 fail the execution instead of overflowing.
+    fn cycles_to_fee(&mut self, cycles: u64, surcharge: u64) -> Result<u64, Error> {
+        cycles.checked_mul(self.env.wei_per_cycle())
+            .and_then(|fee| fee.checked_add(surcharge))
+            .ok_or_else(|| self.fail(FailureReason::OutOfGas(format!("Fee for {} cycles exceeds the maximum fee", cycles))))
     }
     
     pub fn queue_task(
@@ -232,24 +482,28 @@ impl ExecutionContext {
         }));
     }
 
-    fn commit_begin(&mut self) {
+    fn commit_begin(&mut self, title: String) {
         if self.commit_context.is_some() {
             panic!("CommitContext already present");
         }
         self.commit_context = Some(CommitContext {
+            title,
             initial_instruction_counter: ic_cdk::api::instruction_counter(),
+            charged_instruction_counter: ic_cdk::api::instruction_counter(),
             logs: Vec::new(),
             shared_buffer: Vec::new(),
             fees: 0,
         });
     }
 
-    fn commit_end(&mut self, title: String) {
-        let instructions = ic_cdk::api::instruction_counter() - self.commit_context().initial_instruction_counter;
-        // TODO: Handle insufficient funds here without failing the entire execution.
-        self.charge_cycles(instructions);
+    /// Persists the current commit after charging for the instructions that
+    /// haven't been charged yet. The commit is stored even if charging fails.
+    fn commit_end(&mut self, title: String) -> Result<(), Error> {
+        let counter = ic_cdk::api::instruction_counter();
+        let instructions = counter - self.commit_context().initial_instruction_counter;
+        let charged = self.charge_cycles(counter - self.commit_context().charged_instruction_counter);
         let commit = Commit {
-            timestamp: ic_cdk::api::time(),
+            timestamp: self.env.time(),
             title: title,
             logs: self.commit_context().logs.clone(),
             instructions,
@@ -257,13 +511,18 @@ impl ExecutionContext {
         };
         self.env.commit(commit);
         self.commit_context = None;
+        charged
     }
 }
 
 /// Context valid for a single commit of the exeuction.
 pub struct CommitContext {
+    // Title of the commit.
+    pub title: String,
     // Instruction counter at the beginning of the commit.
     pub initial_instruction_counter: u64,
+    // Instruction counter up to which instructions have been charged.
+    pub charged_instruction_counter: u64,
     // Logs written during the current commit.
     pub logs: Vec<LogEntry>,
     // Shared buffer that the guest can read using copy_shared_buffer.
@@ -1,46 +1,112 @@
 use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
+use std::time::Duration;
 
 use alloy::signers::icp::IcpSigner;
-use candid::CandidType;
+use candid::{CandidType, Nat};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 
-use crate::runtime::{Commit, Job, JobRequest, RuntimeEnvironment};
-use crate::runtime::{Execution};
+use crate::runtime::{Commit, FailureReason, Job, JobRequest, RuntimeEnvironment};
+use crate::runtime::{Execution, compile_module};
 
 #[derive(Clone, Debug, CandidType)]
 pub struct SimulationResult {
     pub job: Job,
     pub commits: Vec<Commit>,
     pub error: Option<String>,
+    /// Virtual time that passed during the simulation (nanoseconds). Timers
+    /// resolve right away and advance this clock instead.
+    pub elapsed_time: u64,
+    /// Fees charged for host function calls, grouped by host function.
+    pub host_call_fees: Vec<HostCallFee>,
 }
 
-pub fn simulate_job(request: JobRequest, wasm: &[u8]) -> Result<SimulationResult, String> {
+#[derive(Clone, Debug, CandidType)]
+pub struct HostCallFee {
+    pub name: String,
+    pub calls: u64,
+    pub fees: u64,
+}
+
+/// Breakdown of the cost of a function invocation, based on a simulation.
+#[derive(Clone, Debug, CandidType)]
+pub struct CostEstimate {
+    pub base_fee: u64,
+    /// Fees for the instructions executed, excluding host function calls.
+    pub execution_fees: u64,
+    pub host_call_fees: Vec<HostCallFee>,
+    /// Gas for transactions on the calling chain.
+    pub gas_fees: u64,
+    pub total_cost: u64,
+    /// Gas payment to send with the invocation, including a safety margin.
+    pub gas_payment: Nat,
+    /// Error that occurred during the simulation, in which case the estimate
+    /// only covers the execution up to that point.
+    pub error: Option<String>,
+}
+
+/// Safety margin on top of the simulated cost, in percent. Accounts for
+/// differences between simulation and execution, e.g. in instruction counts.
+const ESTIMATE_SAFETY_MARGIN_PERCENT: u64 = 20;
+
+pub fn simulate_job(request: JobRequest, wasm: &[u8], memory_limit: u64) -> Result<SimulationResult, String> {
+    let (mut result, failure) = run_simulation(request, wasm, memory_limit).map_err(|e| e.to_string())?;
+    result.error = failure.map(|reason| format!("Error during simulation: {}", reason));
+    Ok(result)
+}
+
+/// Simulates the job and returns its result along with the reason the execution
+/// failed after main(), if it did. Fails if the module can't be instantiated or
+/// main() fails.
+pub fn run_simulation(request: JobRequest, wasm: &[u8], memory_limit: u64) -> Result<(SimulationResult, Option<FailureReason>), FailureReason> {
+    let base_fee = crate::pricing::fee_schedule(&request.chain).base_fee;
+    let wei_per_cycle = crate::pricing::wei_per_cycle(&request.chain);
     let env = Rc::new(RefCell::new(SimulationResult {
-        job: Job::new(request),
+        job: Job::new(request, base_fee, wei_per_cycle),
         commits: Vec::new(),
         error: None,
+        elapsed_time: 0,
+        host_call_fees: Vec::new(),
     }));
-    let mut execution = Execution::run_main(wasm, env.clone())?;
+    let module = compile_module(wasm)?;
+    let mut execution = Execution::run_main(&module, memory_limit, env.clone())?;
 
     // spawn_017_compat executes the future until the first actual cansiter call. Since we
     // shouldn't actually have any cansiter calls during simulation, the following
     // block should execute synchronously.
-    let result_local: Rc<RefCell<Option<Result<(), String>>>> = Rc::new(RefCell::new(None));
+    let result_local: Rc<RefCell<Option<Result<(), FailureReason>>>> = Rc::new(RefCell::new(None));
     let result_async = result_local.clone();
     ic_cdk::futures::spawn_017_compat(async move {
         let result = event_loop(&mut execution).await;
         result_async.borrow_mut().replace(result);
     });
 
-    #[allow(unused_must_use)]
-    result_local.borrow().clone().expect("Simulation did not complete synchronously");
+    let event_loop_result = result_local.borrow().clone().expect("Simulation did not complete synchronously");
     let result = env.borrow().clone();
-    Ok(result)
+    Ok((result, event_loop_result.err()))
+}
+
+/// Estimates the cost of the given invocation by simulating it.
+pub fn estimate_cost(request: JobRequest, wasm: &[u8], memory_limit: u64) -> Result<CostEstimate, String> {
+    let result = simulate_job(request, wasm, memory_limit)?;
+    let job = &result.job;
+    let host_call_fees: u64 = result.host_call_fees.iter().map(|fee| fee.fees).sum();
+    let total_cost = job.total_cost();
+    Ok(CostEstimate {
+        base_fee: job.base_fee,
+        execution_fees: job.execution_fees - host_call_fees,
+        host_call_fees: result.host_call_fees.clone(),
+        gas_fees: job.gas_fees,
+        total_cost,
+        gas_payment: Nat::from(total_cost) * (100 + ESTIMATE_SAFETY_MARGIN_PERCENT) / 100u64,
+        error: result.error,
+    })
 }
 
-async fn event_loop(execution: &mut Execution) -> Result<(), String> {
+async fn event_loop(execution: &mut Execution) -> Result<(), FailureReason> {
     let mut futures = FuturesUnordered::new();
     loop {
         while let Some(async_future) = execution.next_queued_future() {
@@ -50,10 +116,7 @@ async fn event_loop(execution: &mut Execution) -> Result<(), String> {
         let async_result = futures.next().await;
         match async_result {
             Some(result) => {
-                let callback_result = execution.callback(result);
-                if callback_result.is_err() {
-                    return Err(format!("Error during simulation: {}", callback_result.unwrap_err()));
-                }
+                execution.callback(result)?;
             },
             None => {
                 return Ok(());
@@ -71,16 +134,47 @@ impl RuntimeEnvironment for Rc<RefCell<SimulationResult>> {
         self.borrow().job.request.clone()
     }
 
-    fn charge_fee(&mut self, fee: u64) -> Result<(), String> {
+    fn charge_fee(&mut self, fee: u64) -> Result<(), FailureReason> {
         // TODO: Make gas balance configurable and check against it.
         self.borrow_mut().job.execution_fees += fee;
         Ok(())
     }
 
-    fn charge_gas(&mut self, gas: u64) -> Result<(), String> {
-        // TODO: Make gas balance configurable and check against it.
-        self.borrow_mut().job.gas_fees += gas;
-        Ok(())
+    fn wei_per_cycle(&self) -> u64 {
+        self.borrow().job.wei_per_cycle
+    }
+
+    fn host_call_surcharge(&self, name: &str) -> u64 {
+        crate::pricing::fee_schedule(&self.borrow().job.request.chain).host_call_surcharge(name)
+    }
+
+    fn record_host_call_fee(&mut self, name: &str, fee: u64) {
+        let mut result = self.borrow_mut();
+        match result.host_call_fees.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) => {
+                entry.calls += 1;
+                entry.fees += fee;
+            }
+            None => result.host_call_fees.push(HostCallFee { name: name.to_string(), calls: 1, fees: fee }),
+        }
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time() + self.borrow().elapsed_time
+    }
+
+    fn sleep(&mut self, delay: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        // Resolve right away, but advance the virtual clock to when the timer was due.
+        let env = self.clone();
+        let due = self.borrow().elapsed_time + delay.as_nanos() as u64;
+        Box::pin(async move {
+            let mut result = env.borrow_mut();
+            result.elapsed_time = result.elapsed_time.max(due);
+        })
+    }
+
+    fn set_result(&mut self, result: Vec<u8>) {
+        self.borrow_mut().job.result = Some(result);
     }
 
     fn commit(&mut self, commit: Commit) {
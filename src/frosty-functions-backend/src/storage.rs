@@ -1,18 +1,35 @@
 use candid::{CandidType, Decode, Encode, Nat};
+use evm_rpc_types::Nat256;
 use ic_stable_structures::log::WriteError;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Bound;
-use ic_stable_structures::{DefaultMemoryImpl, Log, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, Log, Memory as _, StableBTreeMap, Storable};
 use serde::Deserialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
 
-use crate::chain::Chain;
+use crate::access::{RoleChange, RoleGrant};
+use crate::chain::{Address, Chain, EvmChain};
+use crate::consensus::ConsensusAudit;
+use crate::indexer::IndexerState;
+use crate::migration;
+use crate::outbox::OutboxEntry;
+use crate::pricing::{FeeSchedule, PricingState};
+use crate::registry::ChainConfig;
 use crate::repository::{FunctionId, FunctionState};
-use crate::runtime::{Commit, Job, JobRequest, JobStatus, LogEntry};
+use crate::runtime::{Commit, Job, JobFilter, JobPage, JobRequest, JobStatus, JobStatusKind, LogEntry};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+/// Start of the storage header, followed by the schema version (u32, little endian).
+const HEADER_MAGIC: &[u8; 4] = b"FRST";
+
+/// Maximum number of index entries scanned for a page of `list_jobs`.
+const MAX_SCANNED_JOBS: usize = 1_000;
+
+/// Number of consensus audit entries kept, older entries are dropped.
+const MAX_CONSENSUS_AUDITS: u64 = 10_000;
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -38,6 +55,155 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
         )
     );
+
+    // Indexing progress per chain.
+    static INDEXERS: RefCell<StableBTreeMap<EvmChain, IndexerState, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Number of identical RPC responses required per chain.
+    static CONSENSUS_THRESHOLDS: RefCell<StableBTreeMap<EvmChain, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    // MemoryIds 6 and 7 held the former unbounded consensus audit log and are not reused.
+
+    // Jobs that are waiting for their block to be finalized, with their block number.
+    static AWAITING_FINALITY: RefCell<StableBTreeMap<JobKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    static PRICING: RefCell<StableBTreeMap<Chain, PricingState, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    static FEE_SCHEDULES: RefCell<StableBTreeMap<Chain, FeeSchedule, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    // Last job ID assigned per chain, for chains without on-chain job IDs.
+    static JOB_COUNTERS: RefCell<StableBTreeMap<Chain, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // Chain registry entries, overriding the defaults of built-in chains.
+    static CHAIN_REGISTRY: RefCell<StableBTreeMap<EvmChain, ChainConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    // Roles held by principals, with the timestamp they were granted at.
+    static ROLE_GRANTS: RefCell<StableBTreeMap<RoleGrant, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+
+    // Audit log of role changes.
+    static ROLE_CHANGES: RefCell<Log<RoleChange, Memory, Memory>> = RefCell::new(
+        Log::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // Storage header with the schema version, see migration.rs.
+    static HEADER: Memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)));
+
+    // Number of each job in creation order.
+    static JOB_SEQUENCES: RefCell<StableBTreeMap<JobKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // Jobs by their number in creation order.
+    static JOBS_BY_SEQUENCE: RefCell<StableBTreeMap<u64, JobKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // Secondary indexes of jobs by function, caller and status.
+    static JOB_INDEX: RefCell<StableBTreeMap<JobIndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    // Transactions waiting to be sent from the canister's main EVM account, by queue position.
+    static OUTBOX: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    // Most recent disagreements between RPC providers, by sequence number.
+    static CONSENSUS_AUDITS: RefCell<StableBTreeMap<u64, ConsensusAudit, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+}
+
+/// Returns the schema version from the storage header, or 0 if there is no header.
+pub fn get_schema_version() -> u32 {
+    HEADER.with(|memory| {
+        if memory.size() == 0 {
+            return 0;
+        }
+        let mut header = [0u8; 8];
+        memory.read(0, &mut header);
+        if &header[..4] != HEADER_MAGIC {
+            return 0;
+        }
+        u32::from_le_bytes(header[4..].try_into().unwrap())
+    })
+}
+
+pub fn set_schema_version(version: u32) {
+    HEADER.with(|memory| {
+        if memory.size() == 0 && memory.grow(1) < 0 {
+            ic_cdk::trap("Failed to allocate the storage header");
+        }
+        let mut header = HEADER_MAGIC.to_vec();
+        header.extend(version.to_le_bytes());
+        memory.write(0, &header);
+    })
+}
+
+/// Re-encodes all values of the stable maps in their current layout. Log
+/// entries can't be rewritten and are decoded from their original layout.
+pub fn rewrite_values() {
+    FUNCTIONS.with(|p| rewrite_map(&mut p.borrow_mut()));
+    JOBS.with(|p| rewrite_map(&mut p.borrow_mut()));
+    INDEXERS.with(|p| rewrite_map(&mut p.borrow_mut()));
+    PRICING.with(|p| rewrite_map(&mut p.borrow_mut()));
+    FEE_SCHEDULES.with(|p| rewrite_map(&mut p.borrow_mut()));
+    CHAIN_REGISTRY.with(|p| rewrite_map(&mut p.borrow_mut()));
+    CONSENSUS_AUDITS.with(|p| rewrite_map(&mut p.borrow_mut()));
+}
+
+fn rewrite_map<K: Storable + Ord + Clone, V: Storable>(map: &mut StableBTreeMap<K, V, Memory>) {
+    let keys: Vec<K> = map.keys().collect();
+    for key in keys {
+        if let Some(value) = map.get(&key) {
+            map.insert(key, value);
+        }
+    }
 }
 
 pub fn store_function(id: FunctionId, state: FunctionState) -> Option<FunctionState> {
@@ -48,19 +214,83 @@ pub fn get_function(id: FunctionId) -> Option<FunctionState> {
     FUNCTIONS.with(|p| p.borrow_mut().get(&id))
 }
 
+/// Creates a new job awaiting finality. If a job with the same key exists but was
+/// found in a different block, it is replaced as long as it has not been executed
+/// yet, since one of the two blocks must have been orphaned by a re-org.
 pub fn create_job(request: JobRequest) -> bool {
-    JOBS.with(|p| {
+    let key: JobKey = (&request).into();
+    let block_number = request.block_number.unwrap_or_default();
+    let base_fee = crate::pricing::fee_schedule(&request.chain).base_fee;
+    let wei_per_cycle = crate::pricing::wei_per_cycle(&request.chain);
+    let created = JOBS.with(|p| {
         let mut jobs = p.borrow_mut();
-        let key = (&request).into();
-        if jobs.contains_key(&key) {
-            false
-        } else {
-            jobs.insert(key, Job::new(request));
-            true
+        if let Some(existing) = jobs.get(&key) {
+            let replaceable = matches!(existing.status, JobStatus::AwaitingFinality | JobStatus::Reorged);
+            if existing.request.block_hash == request.block_hash || !replaceable {
+                return false;
+            }
+            ic_cdk::println!("Job {:?} on chain {:?} re-appeared in block {:?}, replacing job from block {:?}",
+                key.on_chain_id, key.chain, request.block_hash, existing.request.block_hash);
+            unindex_job(&key, &existing);
         }
+        let mut job = Job::new(request, base_fee, wei_per_cycle);
+        job.status = JobStatus::AwaitingFinality;
+        index_job(&key, &job);
+        jobs.insert(key.clone(), job);
+        true
+    });
+    if created {
+        AWAITING_FINALITY.with(|p| p.borrow_mut().insert(key, block_number));
+    }
+    created
+}
+
+/// Creates a pending job for a request that doesn't need to wait for finality,
+/// e.g. a direct invocation on ICP. Assigns the next job ID of the chain and
+/// returns the request with the ID set.
+pub fn create_direct_job(mut request: JobRequest) -> JobRequest {
+    let job_id = JOB_COUNTERS.with(|p| {
+        let mut counters = p.borrow_mut();
+        let job_id = counters.get(&request.chain).unwrap_or_default() + 1;
+        counters.insert(request.chain.clone(), job_id);
+        job_id
+    });
+    let mut id_bytes = [0u8; 32];
+    id_bytes[24..].copy_from_slice(&job_id.to_be_bytes());
+    request.on_chain_id = Some(Nat256::from_be_bytes(id_bytes));
+
+    let base_fee = crate::pricing::fee_schedule(&request.chain).base_fee;
+    let wei_per_cycle = crate::pricing::wei_per_cycle(&request.chain);
+    let job = Job::new(request.clone(), base_fee, wei_per_cycle);
+    let key: JobKey = (&request).into();
+    index_job(&key, &job);
+    JOBS.with(|p| p.borrow_mut().insert(key, job));
+    request
+}
+
+/// Returns all jobs from the given chain that are waiting for their block to be
+/// finalized and whose block number is at most `max_block_number`.
+pub fn list_awaiting_finality(chain: &Chain, max_block_number: u64) -> Vec<Job> {
+    let keys: Vec<JobKey> = AWAITING_FINALITY.with(|p| {
+        let awaiting = p.borrow();
+        awaiting.keys()
+            .filter(|key| &key.chain == chain)
+            .filter(|key| awaiting.get(key).is_some_and(|block| block <= max_block_number))
+            .collect()
+    });
+    JOBS.with(|p| {
+        let jobs = p.borrow();
+        keys.iter().filter_map(|key| jobs.get(key)).collect()
     })
 }
 
+/// Updates the status of a job that was awaiting finality.
+pub fn finalize_job(job: &JobRequest, status: JobStatus) {
+    let key: JobKey = job.into();
+    AWAITING_FINALITY.with(|p| p.borrow_mut().remove(&key));
+    update_job_status(job, status);
+}
+
 pub fn get_job(chain: &Chain, job_id: Nat) -> Option<Job> {
     let key = JobKey {
         chain: chain.clone(),
@@ -80,12 +310,136 @@ pub fn update_job<R>(job: &JobRequest, f: impl FnOnce(&mut Job) -> R) -> R {
 }
 
 pub fn update_job_status(job: &JobRequest, status: JobStatus) {
-    update_job(job, |job| {
+    let kind = status.kind();
+    let previous = update_job(job, |job| {
         ic_cdk::println!("Updating job status to {:?} for job {:?}", status, job.request.on_chain_id);
-        job.status = status;
+        std::mem::replace(&mut job.status, status).kind()
+    });
+    if previous != kind {
+        reindex_status(&job.into(), previous, kind);
+    }
+}
+
+/// Returns up to `limit` jobs matching the filter in creation order, starting at
+/// `cursor`. At most MAX_SCANNED_JOBS jobs are looked at, so a page can contain
+/// fewer jobs than requested even if more jobs match.
+pub fn list_jobs(filter: &JobFilter, cursor: u64, limit: usize) -> JobPage {
+    // Walk the index of the most selective criterion and check the others on the jobs.
+    let index = match filter {
+        JobFilter { function_hash: Some(hash), .. } => Some(JobIndex::Function(hash.clone())),
+        JobFilter { caller: Some(caller), .. } => Some(JobIndex::Caller(caller.clone())),
+        JobFilter { status: Some(status), .. } => Some(JobIndex::Status(*status)),
+        _ => None,
+    };
+    let sequences: Vec<u64> = match index {
+        Some(index) => JOB_INDEX.with(|p| {
+            let start = JobIndexKey { index: index.clone(), sequence: cursor };
+            let end = JobIndexKey { index, sequence: u64::MAX };
+            p.borrow().keys_range(start..=end).map(|key| key.sequence).take(MAX_SCANNED_JOBS + 1).collect()
+        }),
+        None => JOBS_BY_SEQUENCE.with(|p| {
+            p.borrow().keys_range(cursor..).take(MAX_SCANNED_JOBS + 1).collect()
+        }),
+    };
+
+    let mut page = JobPage { jobs: Vec::new(), next_cursor: None };
+    for (scanned, sequence) in sequences.into_iter().enumerate() {
+        if page.jobs.len() == limit || scanned == MAX_SCANNED_JOBS {
+            page.next_cursor = Some(sequence);
+            break;
+        }
+        let job = JOBS_BY_SEQUENCE.with(|p| p.borrow().get(&sequence))
+            .and_then(|key| JOBS.with(|p| p.borrow().get(&key)));
+        if let Some(job) = job.filter(|job| filter.matches(job)) {
+            page.jobs.push(job);
+        }
+    }
+    page
+}
+
+/// Returns all jobs with the given status in creation order.
+pub fn list_jobs_by_status(status: JobStatusKind) -> Vec<Job> {
+    let keys: Vec<JobKey> = JOB_INDEX.with(|p| {
+        let start = JobIndexKey { index: JobIndex::Status(status), sequence: 0 };
+        let end = JobIndexKey { index: JobIndex::Status(status), sequence: u64::MAX };
+        p.borrow().keys_range(start..=end)
+            .filter_map(|key| JOBS_BY_SEQUENCE.with(|p| p.borrow().get(&key.sequence)))
+            .collect()
+    });
+    JOBS.with(|p| {
+        let jobs = p.borrow();
+        keys.iter().filter_map(|key| jobs.get(key)).collect()
     })
 }
 
+/// Adds all existing jobs to the secondary indexes in order of their creation.
+pub fn index_jobs() {
+    let mut keys: Vec<(u64, JobKey)> = JOBS.with(|p| {
+        let jobs = p.borrow();
+        jobs.keys()
+            .filter_map(|key| jobs.get(&key).map(|job| (job.created_at, key)))
+            .collect()
+    });
+    keys.sort();
+    for (_, key) in keys {
+        if let Some(job) = JOBS.with(|p| p.borrow().get(&key)) {
+            index_job(&key, &job);
+        }
+    }
+}
+
+/// Adds a job to the secondary indexes. Jobs are numbered in creation order,
+/// jobs replaced after a re-org keep their number.
+fn index_job(key: &JobKey, job: &Job) {
+    let sequence = JOB_SEQUENCES.with(|p| {
+        let mut sequences = p.borrow_mut();
+        if let Some(sequence) = sequences.get(key) {
+            return sequence;
+        }
+        let sequence = sequences.len();
+        sequences.insert(key.clone(), sequence);
+        sequence
+    });
+    JOBS_BY_SEQUENCE.with(|p| p.borrow_mut().insert(sequence, key.clone()));
+    JOB_INDEX.with(|p| {
+        let mut index = p.borrow_mut();
+        for entry in index_entries(job) {
+            index.insert(JobIndexKey { index: entry, sequence }, ());
+        }
+    });
+}
+
+fn unindex_job(key: &JobKey, job: &Job) {
+    let Some(sequence) = JOB_SEQUENCES.with(|p| p.borrow().get(key)) else {
+        return;
+    };
+    JOB_INDEX.with(|p| {
+        let mut index = p.borrow_mut();
+        for entry in index_entries(job) {
+            index.remove(&JobIndexKey { index: entry, sequence });
+        }
+    });
+}
+
+fn reindex_status(key: &JobKey, from: JobStatusKind, to: JobStatusKind) {
+    let Some(sequence) = JOB_SEQUENCES.with(|p| p.borrow().get(key)) else {
+        return;
+    };
+    JOB_INDEX.with(|p| {
+        let mut index = p.borrow_mut();
+        index.remove(&JobIndexKey { index: JobIndex::Status(from), sequence });
+        index.insert(JobIndexKey { index: JobIndex::Status(to), sequence }, ());
+    });
+}
+
+fn index_entries(job: &Job) -> [JobIndex; 3] {
+    [
+        JobIndex::Function(job.request.function_hash.clone()),
+        JobIndex::Caller(job.request.caller.clone()),
+        JobIndex::Status(job.status.kind()),
+    ]
+}
+
 pub fn store_commit(job: &JobRequest, commit: &Commit) -> Result<u64, WriteError> {
     let commit_id = COMMITS.with(|p| {
         p.borrow_mut().append(commit)
@@ -98,6 +452,159 @@ pub fn get_commit(commit_id: u64) -> Option<Commit> {
     COMMITS.with(|p| p.borrow_mut().get(commit_id))
 }
 
+pub fn get_indexer(chain: &EvmChain) -> Option<IndexerState> {
+    INDEXERS.with(|p| p.borrow().get(chain))
+}
+
+pub fn store_indexer(chain: EvmChain, state: IndexerState) {
+    INDEXERS.with(|p| p.borrow_mut().insert(chain, state));
+}
+
+pub fn list_indexers() -> Vec<(EvmChain, IndexerState)> {
+    INDEXERS.with(|p| {
+        let indexers = p.borrow();
+        indexers.keys()
+            .filter_map(|chain| indexers.get(&chain).map(|state| (chain, state)))
+            .collect()
+    })
+}
+
+pub fn get_consensus_threshold(chain: &EvmChain) -> Option<u32> {
+    CONSENSUS_THRESHOLDS.with(|p| p.borrow().get(chain))
+}
+
+pub fn store_consensus_threshold(chain: EvmChain, threshold: u32) {
+    CONSENSUS_THRESHOLDS.with(|p| p.borrow_mut().insert(chain, threshold));
+}
+
+/// Appends an audit entry, dropping the oldest entries beyond MAX_CONSENSUS_AUDITS.
+pub fn append_consensus_audit(audit: &ConsensusAudit) {
+    CONSENSUS_AUDITS.with(|p| {
+        let mut audits = p.borrow_mut();
+        let sequence = audits.last_key_value().map_or(0, |(sequence, _)| sequence + 1);
+        audits.insert(sequence, audit.clone());
+        while audits.len() > MAX_CONSENSUS_AUDITS {
+            let Some((oldest, _)) = audits.first_key_value() else { break };
+            audits.remove(&oldest);
+        }
+    });
+}
+
+/// Returns up to `limit` audit entries with a sequence number of at least `offset`.
+/// Entries beyond the retention limit have been dropped, so the first retained
+/// entry may have a sequence number above 0.
+pub fn list_consensus_audits(offset: u64, limit: u64) -> Vec<ConsensusAudit> {
+    CONSENSUS_AUDITS.with(|p| {
+        let audits = p.borrow();
+        audits.keys_range(offset..)
+            .take(limit as usize)
+            .filter_map(|sequence| audits.get(&sequence))
+            .collect()
+    })
+}
+
+pub fn get_pricing(chain: &Chain) -> Option<PricingState> {
+    PRICING.with(|p| p.borrow().get(chain))
+}
+
+pub fn store_pricing(chain: Chain, state: PricingState) {
+    PRICING.with(|p| p.borrow_mut().insert(chain, state));
+}
+
+pub fn list_pricing() -> Vec<(Chain, PricingState)> {
+    PRICING.with(|p| {
+        let pricing = p.borrow();
+        pricing.keys()
+            .filter_map(|chain| pricing.get(&chain).map(|state| (chain, state)))
+            .collect()
+    })
+}
+
+pub fn get_fee_schedule(chain: &Chain) -> Option<FeeSchedule> {
+    FEE_SCHEDULES.with(|p| p.borrow().get(chain))
+}
+
+pub fn store_fee_schedule(chain: Chain, schedule: FeeSchedule) {
+    FEE_SCHEDULES.with(|p| p.borrow_mut().insert(chain, schedule));
+}
+
+pub fn get_chain_config(chain: &EvmChain) -> Option<ChainConfig> {
+    CHAIN_REGISTRY.with(|p| p.borrow().get(chain))
+}
+
+pub fn store_chain_config(chain: EvmChain, config: ChainConfig) {
+    CHAIN_REGISTRY.with(|p| p.borrow_mut().insert(chain, config));
+}
+
+pub fn delete_chain_config(chain: &EvmChain) -> Option<ChainConfig> {
+    CHAIN_REGISTRY.with(|p| p.borrow_mut().remove(chain))
+}
+
+pub fn list_chain_configs() -> Vec<(EvmChain, ChainConfig)> {
+    CHAIN_REGISTRY.with(|p| {
+        let registry = p.borrow();
+        registry.keys()
+            .filter_map(|chain| registry.get(&chain).map(|config| (chain, config)))
+            .collect()
+    })
+}
+
+pub fn has_role_grant(grant: &RoleGrant) -> bool {
+    ROLE_GRANTS.with(|p| p.borrow().contains_key(grant))
+}
+
+/// Stores the grant and returns whether it is new.
+pub fn store_role_grant(grant: RoleGrant) -> bool {
+    ROLE_GRANTS.with(|p| p.borrow_mut().insert(grant, ic_cdk::api::time()).is_none())
+}
+
+/// Deletes the grant and returns whether it existed.
+pub fn delete_role_grant(grant: &RoleGrant) -> bool {
+    ROLE_GRANTS.with(|p| p.borrow_mut().remove(grant).is_some())
+}
+
+pub fn list_role_grants() -> Vec<RoleGrant> {
+    ROLE_GRANTS.with(|p| p.borrow().keys().collect())
+}
+
+pub fn append_role_change(change: &RoleChange) {
+    if let Err(err) = ROLE_CHANGES.with(|p| p.borrow_mut().append(change)) {
+        ic_cdk::println!("ERROR: Failed to store role change: {:?}", err);
+    }
+}
+
+/// Returns up to `limit` role changes starting at index `offset`.
+pub fn list_role_changes(offset: u64, limit: u64) -> Vec<RoleChange> {
+    ROLE_CHANGES.with(|p| {
+        let changes = p.borrow();
+        (offset..changes.len().min(offset.saturating_add(limit)))
+            .filter_map(|index| changes.get(index))
+            .collect()
+    })
+}
+
+pub fn push_outbox_entry(entry: OutboxEntry) {
+    OUTBOX.with(|p| {
+        let mut outbox = p.borrow_mut();
+        let id = outbox.last_key_value().map_or(0, |(id, _)| id + 1);
+        outbox.insert(id, entry);
+    });
+}
+
+/// Returns all entries of the outbox in queue order.
+pub fn list_outbox_entries() -> Vec<(u64, OutboxEntry)> {
+    OUTBOX.with(|p| {
+        let outbox = p.borrow();
+        outbox.keys()
+            .filter_map(|id| outbox.get(&id).map(|entry| (id, entry)))
+            .collect()
+    })
+}
+
+pub fn remove_outbox_entry(id: u64) {
+    OUTBOX.with(|p| p.borrow_mut().remove(&id));
+}
+
 /// Cross-chain Job ID.
 #[derive(Debug, Deserialize, Clone, CandidType, Ord, PartialOrd, PartialEq, Eq)]
 struct JobKey {
@@ -105,6 +612,22 @@ struct JobKey {
     pub on_chain_id: Nat,
 }
 
+/// Criterion of a secondary job index.
+#[derive(Debug, Deserialize, Clone, CandidType, Ord, PartialOrd, PartialEq, Eq)]
+enum JobIndex {
+    Function(FunctionId),
+    Caller(Address),
+    Status(JobStatusKind),
+}
+
+/// Entry of a secondary job index. Entries of the same criterion are ordered by
+/// the number of the job.
+#[derive(Debug, Deserialize, Clone, CandidType, Ord, PartialOrd, PartialEq, Eq)]
+struct JobIndexKey {
+    index: JobIndex,
+    sequence: u64,
+}
+
 impl Into<JobKey> for &JobRequest {
     fn into(self) -> JobKey {
         JobKey {
@@ -115,7 +638,9 @@ impl Into<JobKey> for &JobRequest {
     }
 }
 
-// Implement Storable for a CandidType.
+// Implement Storable for a CandidType used as a key. Keys are stored as raw
+// Candid, since their encoding determines the order of the map and therefore
+// must never change.
 macro_rules! impl_storable {
     ($type: ty) => {
         impl Storable for $type {
@@ -136,8 +661,41 @@ macro_rules! impl_storable {
     };
 }
 
-impl_storable!(FunctionState);
+// Implement Storable for a value stored in a versioned envelope.
+macro_rules! impl_versioned_storable {
+    ($type: ty) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                Cow::Owned(migration::encode(self))
+            }
+
+            fn into_bytes(self) -> Vec<u8> {
+                migration::encode(&self)
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                migration::decode(bytes.as_ref())
+                    .unwrap_or_else(|err| panic!("Failed to decode {}: {}", stringify!($type), err))
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+impl_versioned_storable!(FunctionState);
 impl_storable!(JobKey);  // TODO: Might want to use Bound::FixedSize here.
-impl_storable!(Job);
-impl_storable!(Commit);
+impl_storable!(JobIndexKey);
+impl_versioned_storable!(Job);
+impl_versioned_storable!(Commit);
 impl_storable!(LogEntry);
+impl_storable!(EvmChain);
+impl_versioned_storable!(IndexerState);
+impl_versioned_storable!(ConsensusAudit);
+impl_storable!(Chain);
+impl_versioned_storable!(PricingState);
+impl_versioned_storable!(FeeSchedule);
+impl_versioned_storable!(ChainConfig);
+impl_storable!(RoleGrant);
+impl_versioned_storable!(RoleChange);
+impl_versioned_storable!(OutboxEntry);
@@ -58,7 +58,7 @@ export class FrostyFunctionEditor {
       binary: compilationResult.wasm,
       source: this.code,
       // TODO: Set to something meaningful.
-      compiler: "frosty-ng unstable alpha (client side)"
+      compiler: "frosty-ng unstable alpha (client side)",
     }));
     this.deploying.set(false);
   }
@@ -1,10 +1,11 @@
 import { Injectable } from '@angular/core';
 import { idlFactory } from 'declarations/frosty-functions-backend';
-import { _SERVICE, JobRequest, FunctionDefinition, DeployResult, FunctionState, Chain, Result_1, Result, Job, Commit, SimulationResult } from 'declarations/frosty-functions-backend/frosty-functions-backend.did';
+import { _SERVICE, JobRequest, FunctionDefinition, DeployResult, FunctionState, Chain, Result_1, Job, Commit, SimulationResult } from 'declarations/frosty-functions-backend/frosty-functions-backend.did';
 import { Actor, ActorMethodMappedExtended, ActorSubclass, HttpAgent } from '@icp-sdk/core/agent';
 import { FROSTY_SOURCES, RUNTIME_SOURCE } from '../../../assembly/sources';
 import { decodeHex, encodeHex } from './util';
-import { TransactionReceipt } from 'ethers';
+import { Interface, TransactionReceipt } from 'ethers';
+import bridgeAbi from '../../../../contracts/Bride.abi.json';
 import { interval, Observable, switchMap, takeWhile } from 'rxjs';
 import type { APIOptions } from 'assemblyscript/asc';
 
@@ -121,6 +122,7 @@ export class FrostyFunctionService {
       on_chain_id: [BigInt(42)],
       data: decodeHex("0xdeadbeef"),
       gas_payment: BigInt(0),
+      callback: [],
     };
 
     const response = await (await (await this.actor()).simulate_execution(request, wasm)).result;
@@ -130,9 +132,12 @@ export class FrostyFunctionService {
     return { canisterId: CANISTER_ID, ...response.Ok }
   }
 
-  async deploy(definition: FunctionDefinition): Promise<DeploymentResult> {
-    const apiKey = (globalThis as any).frostyApiKey;
-    const result = await (await (await this.actor()).deploy_function(definition, apiKey ? [apiKey] : []) as any).result;
+  /**
+   * Deploys the function. The editor calls the canister anonymously, so this only
+   * succeeds if the anonymous principal has been granted the Deployer role.
+   */
+  async deploy(definition: FunctionDefinition, memoryLimit?: bigint): Promise<DeploymentResult> {
+    const result = await (await (await this.actor()).deploy_function(definition, memoryLimit === undefined ? [] : [memoryLimit]) as any).result;
     if ('Error' in result) {
       return { error: `${result.Error}` };
     } else if ('Duplicate' in result) {
@@ -151,21 +156,16 @@ export class FrostyFunctionService {
   }
 
   /**
-   * Asks the backend to index the given transaction. Resolves to the JobRequest created,
-   * or rejects if indexing failed.
+   * Extracts the job ID from the FunctionInvoked event in the given receipt. The job
+   * itself is created once the backend indexer has picked up the block.
    */
-  async indexTransaction(chain: Chain, receipt: TransactionReceipt): Promise<JobRequest> {
-    // TODO: Why is type not inferred correctly here?
-    const response: {result: Promise<Result>} = await (await this.actor()).index_block(chain, receipt.blockNumber) as any;
-    const result: Result = await response.result;
-    if ('Err' in result) throw new Error(`${result.Err}`);
-
-    // There might be JobRequests for other transactions in the same block; filter them out
-    const jobRequests = result.Ok.filter(req => req.transaction_hash && req.transaction_hash[0] === receipt.hash);
-    if (jobRequests.length != 1) {
-      throw new Error(`Expected exactly one JobRequest for transaction ${receipt.hash}, got ${jobRequests.length}`);
+  jobIdFromReceipt(receipt: TransactionReceipt): number {
+    const bridge = new Interface(bridgeAbi);
+    for (const log of receipt.logs) {
+      const event = bridge.parseLog(log);
+      if (event?.name === 'FunctionInvoked') return Number(event.args['jobId']);
     }
-    return jobRequests[0];
+    throw new Error(`No FunctionInvoked event found in transaction ${receipt.hash}`);
   }
 
   // TODO: Implement watchJob that watches until job is complete.
@@ -181,13 +181,14 @@ export class FrostyFunctionService {
   }
 
   /**
-   * Watches the given job until its status is either Completed or Failed.
+   * Watches the given job until its status is either Completed, Failed or Reorged.
+   * Keeps polling while the job has not been indexed yet.
    */
   watchJob(chain: Chain, jobId: number): Observable<Job | null> {
     const pollInterval = 1000;  // Poll every second
     return interval(pollInterval).pipe(
       switchMap(() => this.getJob(chain, jobId)),
-      takeWhile(job => !!(job && !('Completed' in job.status) && !("Failed" in job.status)), true)
+      takeWhile(job => !job || (!('Completed' in job.status) && !("Failed" in job.status) && !("Reorged" in job.status)), true)
     );
   }
 
@@ -206,6 +207,11 @@ export class FrostyFunctionService {
       if ('Localhost' in chain.Evm) return 31337;
       if ('ArbitrumOne' in chain.Evm) return 42161;
       if ('ArbitrumSepolia' in chain.Evm) return 421614;
+      if ('EthereumMainnet' in chain.Evm) return 1;
+      if ('EthereumSepolia' in chain.Evm) return 11155111;
+      if ('Base' in chain.Evm) return 8453;
+      if ('Optimism' in chain.Evm) return 10;
+      if ('Custom' in chain.Evm) return Number(chain.Evm.Custom);
     }
     throw new Error(`Unsupported chain: ${chain}`);
   }
@@ -27,4 +27,5 @@ export class FunctionOverviewComponent {
 
   formatTimestamp = formatTimestamp
   encodeHex = encodeHex;
+  Number = Number;
 }
@@ -106,8 +106,8 @@ export class InvokeFunctionComponent {
     this.job.set('pending');
     try {
       const chain = this.getSelectedChain();
-      const jobRequest = await this.frostyFunctionService.indexTransaction(chain, receipt);
-      this.job.set(this.frostyFunctionService.watchJob(chain, Number(jobRequest.on_chain_id[0])));
+      const jobId = this.frostyFunctionService.jobIdFromReceipt(receipt);
+      this.job.set(this.frostyFunctionService.watchJob(chain, jobId));
     } catch (e) {
       this.error.set(`Indexing transaction failed: ${e}`);
       this.job.set(null);
@@ -1,6 +1,6 @@
 import { Component, signal, Signal } from '@angular/core';
 import { ActivatedRoute } from '@angular/router';
-import { Chain, Commit, Job } from 'declarations/frosty-functions-backend/frosty-functions-backend.did';
+import { Chain, Commit, Delivery, Job } from 'declarations/frosty-functions-backend/frosty-functions-backend.did';
 import { FrostyFunctionService } from '../frosty-function-service';
 import { encodeHex, formatTimestamp } from '../util';
 import { JsonPipe } from '@angular/common';
@@ -37,12 +37,24 @@ export class JobComponent {
     switch (chainId) {
       case 'eip155:31337':
         return { Evm: { Localhost: null } };
+      case 'eip155:1':
+        return { Evm: { EthereumMainnet: null } };
+      case 'eip155:11155111':
+        return { Evm: { EthereumSepolia: null } };
+      case 'eip155:8453':
+        return { Evm: { Base: null } };
+      case 'eip155:10':
+        return { Evm: { Optimism: null } };
       case 'eip155:42161':
         return { Evm: { ArbitrumOne: null } };
       case 'eip155:421614':
         return { Evm: { ArbitrumSepolia: null } };
+      case 'icp':
+        return { Icp: null };
       default:
-        return null;
+        // Chains from the chain registry.
+        const match = /^eip155:(\d+)$/.exec(chainId);
+        return match ? { Evm: { Custom: BigInt(match[1]) } } : null;
     }
   }
 
@@ -63,11 +75,18 @@ export class JobComponent {
       if ('Localhost' in chain.Evm) return "Localhost EVM Node";
       if ('ArbitrumOne' in chain.Evm) return "Arbitrum One";
       if ('ArbitrumSepolia' in chain.Evm) return "Arbitrum Sepolia Testnet";
+      if ('EthereumMainnet' in chain.Evm) return "Ethereum Mainnet";
+      if ('EthereumSepolia' in chain.Evm) return "Ethereum Sepolia Testnet";
+      if ('Base' in chain.Evm) return "Base";
+      if ('Optimism' in chain.Evm) return "OP Mainnet";
+      if ('Custom' in chain.Evm) return `EVM Chain ${chain.Evm.Custom}`;
     }
+    if ('Icp' in chain) return "Internet Computer";
     return "Unknown Chain";
   }
 
   chainId(chain: Chain): string {
+    if ('Icp' in chain) return 'icp';
     return this.service.chainId(chain).toString();
   }
 
@@ -76,6 +95,25 @@ export class JobComponent {
     return Object.keys(job.status)[0].toLowerCase();
   }
 
+  failureReason(job: Job): string {
+    const reason: any = (job.status as any).Failed;
+    const [kind, detail] = Object.entries(reason)[0];
+    if (kind === 'OutOfMemory') return `Out of memory: Memory is limited to ${(detail as any).limit} bytes`;
+    if (kind === 'InsufficientPayment') return `Gas payment below minimum of ${(detail as any).min_payment}`;
+    if (kind === 'FunctionNotFound') return 'Function not found';
+    return `${kind}: ${detail}`;
+  }
+
+  deliveryStatus(delivery: Delivery): string {
+    if ('Delivered' in delivery.status) return 'Delivered';
+    if ('Failed' in delivery.status) return `Failed (${delivery.status.Failed})`;
+    return 'Pending';
+  }
+
+  callerPrincipal(job: Job): string | null {
+    return 'Principal' in job.request.caller ? job.request.caller.Principal.toText() : null;
+  }
+
   formatTimestamp = formatTimestamp;
   encodeHex = encodeHex;
   SCANNER_URL = SCANNER_URL;